@@ -0,0 +1,3 @@
+ALTER TABLE uploads ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE uploads ADD COLUMN last_error TEXT;
+ALTER TABLE uploads ADD COLUMN next_attempt_at DATETIME;
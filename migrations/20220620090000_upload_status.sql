@@ -0,0 +1,39 @@
+CREATE TABLE uploads_new (
+    id INTEGER NOT NULL PRIMARY KEY,
+    event_id TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'queued',
+    avid INTEGER,
+    archive TEXT,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at DATETIME,
+    created_at DATETIME NOT NULL,
+    updated_at DATETIME NOT NULL,
+    finished_at DATETIME
+);
+
+INSERT INTO uploads_new (id, event_id, status, avid, archive, attempts, last_error, next_attempt_at, created_at, updated_at, finished_at)
+SELECT id, event_id,
+    CASE
+        WHEN uploaded = 1 THEN 'submitted'
+        WHEN failed_at IS NOT NULL THEN 'failed'
+        ELSE 'queued'
+    END,
+    avid, archive, attempts, last_error, next_attempt_at, created_at,
+    COALESCE(finished_at, failed_at, created_at), finished_at
+FROM uploads;
+
+DROP TABLE uploads;
+ALTER TABLE uploads_new RENAME TO uploads;
+
+CREATE TABLE upload_transitions (
+    id INTEGER NOT NULL PRIMARY KEY,
+    event_id TEXT NOT NULL,
+    status TEXT NOT NULL,
+    created_at DATETIME NOT NULL
+);
+
+INSERT INTO upload_transitions (event_id, status, created_at)
+SELECT event_id, status, updated_at FROM uploads;
+
+CREATE INDEX upload_transitions_event_id ON upload_transitions (event_id);
@@ -0,0 +1,3 @@
+ALTER TABLE events ADD COLUMN session_id TEXT;
+
+CREATE INDEX events_session_id ON events (session_id);
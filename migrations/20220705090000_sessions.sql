@@ -0,0 +1,17 @@
+CREATE TABLE sessions (
+    session_id TEXT NOT NULL PRIMARY KEY,
+    room_id INTEGER NOT NULL,
+    name TEXT NOT NULL,
+    title TEXT NOT NULL,
+    started_at DATETIME,
+    ended_at DATETIME
+);
+
+CREATE TABLE recorder_events (
+    event_id TEXT NOT NULL PRIMARY KEY,
+    event_type TEXT NOT NULL,
+    room_id INTEGER NOT NULL,
+    session_id TEXT,
+    payload TEXT NOT NULL,
+    received_at DATETIME NOT NULL
+);
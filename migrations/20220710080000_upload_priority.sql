@@ -0,0 +1 @@
+ALTER TABLE uploads ADD COLUMN priority INTEGER NOT NULL DEFAULT 0;
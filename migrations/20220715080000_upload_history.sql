@@ -0,0 +1,6 @@
+ALTER TABLE uploads ADD COLUMN bvid TEXT;
+ALTER TABLE uploads ADD COLUMN part_title TEXT;
+
+UPDATE uploads SET part_title = json_extract(video, '$.title') WHERE video IS NOT NULL;
+
+CREATE INDEX uploads_created_at ON uploads (created_at);
@@ -0,0 +1,4 @@
+-- Room 0 stands for the whole queue.
+CREATE TABLE paused (
+    room_id INTEGER NOT NULL PRIMARY KEY
+);
@@ -0,0 +1,236 @@
+use std::fmt;
+use std::path::Path;
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone};
+use log::warn;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::config::ManagerConfig;
+use crate::recorder::{
+    EventType, RecorderEventData, RecorderPayload, RecorderWebhook, RoomInfo, SessionData,
+};
+
+/// Normalizes the webhooks of a recorder into [`RecorderWebhook`].
+pub trait RecorderAdapter: Sync {
+    /// Used in the route, e.g. `/recorder/blrec`.
+    fn name(&self) -> &'static str;
+
+    /// Whether a payload looks like it comes from this recorder.
+    fn sniff(&self, payload: &Value) -> bool;
+
+    /// `None` for events that are of no interest to the manager. Fails with
+    /// [`FileEventError`] for file events that cannot be handled, and with
+    /// anything else for malformed payloads of known events.
+    ///
+    /// May look at the recorded file, so this blocks.
+    fn parse(&self, payload: Value, config: &ManagerConfig) -> Result<Option<RecorderWebhook>>;
+}
+
+/// A file event that was understood but cannot be turned into an upload, e.g.
+/// because the file is outside of `rec_dir`. Unlike malformed payloads, these
+/// are reported to the recorder rather than ignored.
+#[derive(Debug)]
+pub struct FileEventError(pub anyhow::Error);
+
+impl fmt::Display for FileEventError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl std::error::Error for FileEventError {}
+
+static ADAPTERS: &[&dyn RecorderAdapter] = &[&BililiveRecorder, &Blrec];
+
+pub fn by_name(name: &str) -> Option<&'static dyn RecorderAdapter> {
+    ADAPTERS
+        .iter()
+        .copied()
+        .find(|adapter| adapter.name() == name)
+}
+
+pub fn sniff(payload: &Value) -> Option<&'static dyn RecorderAdapter> {
+    ADAPTERS
+        .iter()
+        .copied()
+        .find(|adapter| adapter.sniff(payload))
+}
+
+/// BililiveRecorder webhook v2, which is also the internal event model.
+pub struct BililiveRecorder;
+
+impl RecorderAdapter for BililiveRecorder {
+    fn name(&self) -> &'static str {
+        "bililive"
+    }
+
+    fn sniff(&self, payload: &Value) -> bool {
+        payload.get("EventType").is_some() && payload.get("EventData").is_some()
+    }
+
+    fn parse(&self, payload: Value, _config: &ManagerConfig) -> Result<Option<RecorderWebhook>> {
+        // Event types added by newer versions.
+        if EventType::deserialize(&payload["EventType"]).is_err() {
+            return Ok(None);
+        }
+        Ok(Some(serde_json::from_value(payload)?))
+    }
+}
+
+/// blrec webhooks.
+///
+/// blrec does not send room information with file events, so the name and
+/// title of the streamer are left empty and filled in from the live session.
+pub struct Blrec;
+
+#[derive(Debug, Deserialize)]
+struct BlrecEvent {
+    id: String,
+    date: String,
+    #[serde(rename = "type")]
+    event_type: String,
+    data: Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlrecLiveData {
+    user_info: BlrecUserInfo,
+    room_info: BlrecRoomInfo,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlrecUserInfo {
+    name: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlrecRoomInfo {
+    room_id: u64,
+    #[serde(default)]
+    short_room_id: u64,
+    title: String,
+    #[serde(default)]
+    parent_area_name: String,
+    #[serde(default)]
+    area_name: String,
+    live_start_time: i64,
+}
+
+#[derive(Debug, Deserialize)]
+struct BlrecFileData {
+    room_id: u64,
+    path: String,
+}
+
+impl BlrecLiveData {
+    fn into_session(self, streaming: bool) -> SessionData {
+        let room = self.room_info;
+        SessionData {
+            session_id: format!("blrec-{}-{}", room.room_id, room.live_start_time),
+            room: RoomInfo {
+                room_id: room.room_id,
+                short_id: room.short_room_id,
+                name: self.user_info.name,
+                title: room.title,
+                area_name_parent: room.parent_area_name,
+                area_name_child: room.area_name,
+                recording: streaming,
+                streaming,
+                danmaku_connected: false,
+            },
+        }
+    }
+}
+
+impl RecorderAdapter for Blrec {
+    fn name(&self) -> &'static str {
+        "blrec"
+    }
+
+    fn sniff(&self, payload: &Value) -> bool {
+        payload.get("type").is_some()
+            && payload.get("data").is_some()
+            && payload.get("date").is_some()
+    }
+
+    fn parse(&self, payload: Value, config: &ManagerConfig) -> Result<Option<RecorderWebhook>> {
+        let event: BlrecEvent = serde_json::from_value(payload)?;
+
+        let payload = match event.event_type.as_str() {
+            "LiveBeganEvent" => {
+                let data: BlrecLiveData = serde_json::from_value(event.data)?;
+                RecorderPayload::SessionStarted(data.into_session(true))
+            }
+            "LiveEndedEvent" => {
+                let data: BlrecLiveData = serde_json::from_value(event.data)?;
+                RecorderPayload::SessionEnded(data.into_session(false))
+            }
+            "VideoPostprocessingCompletedEvent" => {
+                let data: BlrecFileData = serde_json::from_value(event.data)?;
+                let closed_at = DateTime::parse_from_rfc3339(&event.date)?;
+                let data = file_data(data, closed_at, config).map_err(FileEventError)?;
+                RecorderPayload::FileClosed(data)
+            }
+            _ => return Ok(None),
+        };
+
+        Ok(Some(RecorderWebhook {
+            event_id: event.id,
+            event_timestamp: Some(event.date),
+            payload,
+        }))
+    }
+}
+
+fn file_data(
+    data: BlrecFileData,
+    closed_at: DateTime<FixedOffset>,
+    config: &ManagerConfig,
+) -> Result<RecorderEventData> {
+    let path = Path::new(&data.path);
+    let relative_path = path
+        .strip_prefix(&config.rec_dir)
+        .map_err(|_| anyhow!("{} is not under {}", data.path, config.rec_dir))?;
+
+    // Files are named `..._YYYY-MM-DD-HHMMSS` by default, in the local time
+    // of the recorder. Without it, only the time of completion is known.
+    let opened_at = path
+        .file_stem()
+        .and_then(|stem| stem.to_str())
+        .and_then(|stem| stem.get(stem.len().checked_sub(17)?..))
+        .and_then(|time| NaiveDateTime::parse_from_str(time, "%Y-%m-%d-%H%M%S").ok())
+        .and_then(|time| closed_at.offset().from_local_datetime(&time).single());
+    let (opened_at, duration, duration_unknown) = match opened_at {
+        Some(opened_at) => {
+            let duration = (closed_at - opened_at).num_milliseconds() as f64 / 1000.0;
+            (opened_at, duration, false)
+        }
+        None => {
+            warn!(
+                "No start time in the name of {}, its duration is unknown",
+                data.path
+            );
+            (closed_at, 0.0, true)
+        }
+    };
+
+    let mut event_data = RecorderEventData {
+        room_id: data.room_id,
+        name: String::new(),
+        title: String::new(),
+        relative_path: relative_path.to_string_lossy().to_string(),
+        file_open_time: opened_at.to_rfc3339(),
+        file_size: 0,
+        duration,
+        duration_unknown,
+        session_id: None,
+    };
+    // The path comes from the network. Checked before the file is looked
+    // up, so that the answer tells nothing about files elsewhere.
+    event_data.check_relative_path()?;
+    event_data.file_size = std::fs::metadata(path)?.len();
+
+    Ok(event_data)
+}
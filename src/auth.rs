@@ -0,0 +1,356 @@
+use std::sync::Arc;
+
+use actix_web::{dev::Payload, http::header, web, FromRequest, HttpRequest};
+use futures::future::{ready, Ready};
+use hmac::{Hmac, Mac};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+
+use crate::config::{ManagerConfig, SharedConfig};
+use crate::error::{ApiError, ApiResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Role {
+    /// Status, history and metrics.
+    Read,
+    /// Everything, including retrying and cancelling uploads.
+    Admin,
+}
+
+/// Extractor for routes that only read state.
+pub(crate) struct Reader;
+
+/// Extractor for routes that change the queue.
+pub(crate) struct Admin;
+
+/// Extractor for the event stream, a reader route that also takes its token
+/// from the query, as `EventSource` in browsers cannot set headers.
+pub(crate) struct Subscriber;
+
+/// Extractor for the recorder webhook routes.
+pub(crate) struct Recorder;
+
+#[derive(Deserialize)]
+struct TokenQuery {
+    token: Option<String>,
+}
+
+impl FromRequest for Reader {
+    type Error = ApiError;
+    type Future = Ready<ApiResult<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authorize(req, Role::Read, false).map(|_| Reader))
+    }
+}
+
+impl FromRequest for Subscriber {
+    type Error = ApiError;
+    type Future = Ready<ApiResult<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authorize(req, Role::Read, true).map(|_| Subscriber))
+    }
+}
+
+impl FromRequest for Admin {
+    type Error = ApiError;
+    type Future = Ready<ApiResult<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(authorize(req, Role::Admin, false).map(|_| Admin))
+    }
+}
+
+impl FromRequest for Recorder {
+    type Error = ApiError;
+    type Future = Ready<ApiResult<Self>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        if let Err(e) = check_allowlist(req) {
+            return ready(Err(e));
+        }
+
+        let config = current_config(req);
+        let secret = config
+            .as_ref()
+            .and_then(|config| config.auth.webhook_secret.as_deref());
+        let result = match (secret, presented_token(req, true)) {
+            (None, _) => Ok(Recorder),
+            (Some(secret), Some(token))
+                if constant_time_eq(secret.as_bytes(), token.as_bytes()) =>
+            {
+                Ok(Recorder)
+            }
+            (Some(_), Some(_)) => Err(ApiError::unauthorized(
+                "invalid_token",
+                "Invalid webhook secret",
+            )),
+            (Some(_), None) => Err(ApiError::unauthorized(
+                "missing_token",
+                "Webhook secret required",
+            )),
+        };
+        ready(result)
+    }
+}
+
+fn check_allowlist(req: &HttpRequest) -> ApiResult<()> {
+    let config = current_config(req);
+    let allowlist = match &config {
+        Some(config) if !config.auth.webhook_allowlist.is_empty() => &config.auth.webhook_allowlist,
+        _ => return Ok(()),
+    };
+
+    let peer = req.peer_addr().map(|addr| addr.ip());
+    match peer {
+        Some(ip) if allowlist.iter().any(|net| net.contains(&ip)) => Ok(()),
+        _ => {
+            warn!("Rejected webhook from {:?}", peer);
+            Err(ApiError::forbidden("forbidden", "Address not allowed"))
+        }
+    }
+}
+
+/// Check the signature of a recorder webhook, before anything in the body
+/// is trusted.
+pub(crate) fn verify_signature(req: &HttpRequest, body: &[u8]) -> ApiResult<()> {
+    let config = current_config(req);
+    let hmac = match config
+        .as_ref()
+        .and_then(|config| config.auth.webhook_hmac.as_ref())
+    {
+        Some(hmac) => hmac,
+        None => return Ok(()),
+    };
+
+    let header = |name: &str| {
+        req.headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .ok_or_else(|| {
+                ApiError::unauthorized("missing_signature", format!("Missing header {}", name))
+            })
+    };
+    let timestamp = header(&hmac.timestamp_header)?;
+    let signature = header(&hmac.header)?;
+
+    let ts: i64 = timestamp.parse().map_err(|_| {
+        ApiError::bad_request("bad_timestamp", format!("Invalid timestamp {}", timestamp))
+    })?;
+    let skew = (chrono::Utc::now().timestamp() - ts).unsigned_abs();
+    if skew > hmac.max_skew {
+        return Err(ApiError::unauthorized(
+            "stale_signature",
+            format!("Timestamp is off by {}s", skew),
+        ));
+    }
+
+    let signature = signature.strip_prefix("sha256=").unwrap_or(signature);
+    let signature = hex::decode(signature)
+        .map_err(|_| ApiError::unauthorized("invalid_signature", "Malformed signature"))?;
+
+    let mut mac = Hmac::<Sha256>::new_from_slice(hmac.secret.as_bytes())
+        .map_err(|e| anyhow::anyhow!("Invalid HMAC secret: {}", e))?;
+    mac.update(timestamp.as_bytes());
+    mac.update(b".");
+    mac.update(body);
+    // `verify_slice` compares in constant time.
+    mac.verify_slice(&signature).map_err(|_| {
+        warn!(
+            "Rejected webhook with an invalid signature from {:?}",
+            req.peer_addr()
+        );
+        ApiError::unauthorized("invalid_signature", "Invalid signature")
+    })
+}
+
+fn current_config(req: &HttpRequest) -> Option<Arc<ManagerConfig>> {
+    req.app_data::<web::Data<SharedConfig>>()
+        .map(|config| config.load_full())
+}
+
+fn authorize(req: &HttpRequest, required: Role, allow_query: bool) -> ApiResult<()> {
+    let config = current_config(req);
+    let tokens = match &config {
+        Some(config) if !config.auth.tokens.is_empty() => &config.auth.tokens,
+        _ => return Ok(()),
+    };
+
+    let presented = presented_token(req, allow_query)
+        .ok_or_else(|| ApiError::unauthorized("missing_token", "Bearer token required"))?;
+    let token = tokens
+        .iter()
+        .find(|token| constant_time_eq(token.token.as_bytes(), presented.as_bytes()))
+        .ok_or_else(|| ApiError::unauthorized("invalid_token", "Invalid bearer token"))?;
+
+    if token.role < required {
+        debug!("Token {} denied access to {}", token.name, req.path());
+        return Err(ApiError::forbidden(
+            "forbidden",
+            format!("Token {} is not allowed to do this", token.name),
+        ));
+    }
+
+    Ok(())
+}
+
+/// The bearer token of the request. With `allow_query`, a `token` query
+/// parameter is accepted as well, for the routes whose clients cannot set
+/// headers: the event stream and the recorder webhooks. Elsewhere it would
+/// only leak tokens into access logs.
+fn presented_token(req: &HttpRequest, allow_query: bool) -> Option<String> {
+    let bearer = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+        .map(|token| token.trim().to_string());
+
+    bearer.or_else(|| {
+        if !allow_query {
+            return None;
+        }
+        web::Query::<TokenQuery>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().token)
+    })
+}
+
+/// Compare secrets without leaking how much of them matches through timing.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::test::TestRequest;
+    use arc_swap::ArcSwap;
+
+    use super::*;
+    use crate::test_util;
+
+    const BODY: &[u8] = br#"{"EventType":"FileClosed"}"#;
+
+    fn request(headers: &[(&str, String)]) -> HttpRequest {
+        request_to("/recorder", headers)
+    }
+
+    fn request_to(uri: &str, headers: &[(&str, String)]) -> HttpRequest {
+        let config = test_util::config(
+            "auth:
+  tokens:
+    - name: grafana
+      token: r3ad
+      role: read
+  webhook_secret: w3bhook
+  webhook_hmac:
+    secret: s3cret
+",
+        );
+        let mut req = TestRequest::get()
+            .uri(uri)
+            .app_data(web::Data::new(ArcSwap::from_pointee(config)));
+        for (name, value) in headers {
+            req = req.insert_header((*name, value.as_str()));
+        }
+        req.to_http_request()
+    }
+
+    fn sign(timestamp: &str, body: &[u8]) -> String {
+        let mut mac = Hmac::<Sha256>::new_from_slice(b"s3cret").unwrap();
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        hex::encode(mac.finalize().into_bytes())
+    }
+
+    fn now() -> String {
+        chrono::Utc::now().timestamp().to_string()
+    }
+
+    fn error_code(result: ApiResult<()>) -> String {
+        let error = result.unwrap_err().to_string();
+        error.split(':').next().unwrap().to_string()
+    }
+
+    #[test]
+    fn accepts_good_signature() {
+        let ts = now();
+        let req = request(&[
+            ("X-Timestamp", ts.clone()),
+            ("X-Signature", sign(&ts, BODY)),
+        ]);
+        assert!(verify_signature(&req, BODY).is_ok());
+    }
+
+    #[test]
+    fn accepts_sha256_prefix() {
+        let ts = now();
+        let signature = format!("sha256={}", sign(&ts, BODY));
+        let req = request(&[("X-Timestamp", ts), ("X-Signature", signature)]);
+        assert!(verify_signature(&req, BODY).is_ok());
+    }
+
+    #[test]
+    fn rejects_bad_signature() {
+        let ts = now();
+        let req = request(&[
+            ("X-Timestamp", ts.clone()),
+            ("X-Signature", sign(&ts, b"{}")),
+        ]);
+        assert_eq!(
+            error_code(verify_signature(&req, BODY)),
+            "invalid_signature"
+        );
+    }
+
+    #[test]
+    fn rejects_stale_timestamp() {
+        let ts = (chrono::Utc::now().timestamp() - 3600).to_string();
+        let req = request(&[
+            ("X-Timestamp", ts.clone()),
+            ("X-Signature", sign(&ts, BODY)),
+        ]);
+        assert_eq!(error_code(verify_signature(&req, BODY)), "stale_signature");
+    }
+
+    #[test]
+    fn rejects_missing_headers() {
+        let ts = now();
+        let req = request(&[("X-Timestamp", ts.clone())]);
+        assert_eq!(
+            error_code(verify_signature(&req, BODY)),
+            "missing_signature"
+        );
+        let req = request(&[("X-Signature", sign(&ts, BODY))]);
+        assert_eq!(
+            error_code(verify_signature(&req, BODY)),
+            "missing_signature"
+        );
+    }
+
+    #[test]
+    fn takes_query_tokens_only_where_headers_cannot_be_set() {
+        let bearer = [("Authorization", "Bearer r3ad".to_string())];
+        assert!(Reader::extract(&request_to("/status", &bearer))
+            .into_inner()
+            .is_ok());
+        assert!(Reader::extract(&request_to("/status?token=r3ad", &[]))
+            .into_inner()
+            .is_err());
+        assert!(Subscriber::extract(&request_to("/events?token=r3ad", &[]))
+            .into_inner()
+            .is_ok());
+        assert!(
+            Recorder::extract(&request_to("/recorder?token=w3bhook", &[]))
+                .into_inner()
+                .is_ok()
+        );
+    }
+}
@@ -1,4 +1,4 @@
-use biliupmgr::recorder::{RecorderEvent, RecorderEventData};
+use biliupmgr::recorder::{EventType, RecorderEvent, RecorderEventData};
 use byteorder::{BigEndian, ByteOrder};
 use clap::Parser;
 use regex::Regex;
@@ -55,7 +55,7 @@ impl UploadFileParser for RecorderEvent {
 
         Some(RecorderEvent {
             event_id: Uuid::new_v4().to_string(),
-            event_type: "FileClosed".to_string(),
+            event_type: EventType::FileClosed,
             event_data: RecorderEventData {
                 room_id,
                 name,
@@ -64,6 +64,8 @@ impl UploadFileParser for RecorderEvent {
                 file_open_time,
                 file_size,
                 duration,
+                duration_unknown: false,
+                session_id: None,
             },
         })
     }
@@ -1,43 +1,512 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
 
-use serde::{Deserialize, Serialize};
+use anyhow::{anyhow, bail};
+use arc_swap::ArcSwap;
+use ipnet::IpNet;
+use log::warn;
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_yaml::{Mapping, Value};
 
-#[derive(Debug, Serialize, Deserialize)]
+use crate::auth::Role;
+use crate::upgrade;
+use crate::upload::ErrorClass;
+
+/// Upload settings of a room. Fields left out are inherited, see
+/// [`ManagerConfig::load`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RoomConfig {
     pub room_id: u64,
+    /// Entry of `profiles` to inherit fields from, before `defaults`.
+    #[serde(default)]
+    pub profile: Option<String>,
+    /// Fields taken from the profile or `defaults`, and which one. Filled in
+    /// by [`ManagerConfig::load`], not read from the file.
+    #[serde(skip_deserializing)]
+    pub inherited: BTreeMap<String, String>,
     pub user_cookie: String,
     pub studio_title: String,
     pub part_title: String,
     pub cover: String,
     pub description: String,
-    pub tags: String,
+    pub tags: Vec<String>,
     pub tid: u16,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ManagerConfig {
+    /// Schema version, see [`crate::upgrade`].
     pub version: u32,
     pub host: String,
     pub port: u16,
     pub rec_dir: String,
+    /// Other directories that recordings under `rec_dir` may link to.
+    #[serde(default)]
+    pub extra_rec_dirs: Vec<String>,
     #[serde(default = "default_limit")]
     pub limit: usize,
+    /// Upload lines, tried in order until one succeeds.
     #[serde(default = "default_line")]
-    pub line: String,
+    pub line: Vec<String>,
+    /// Seconds between two recordings of a room for them to be considered
+    /// the same live session, when the recorder does not tell.
+    #[serde(default = "default_session_gap")]
+    pub session_gap: u64,
+    /// Seconds between two checks of the review of submitted archives.
+    #[serde(default = "default_review_interval")]
+    pub review_interval: u64,
+    #[serde(default)]
+    pub retry: RetryConfig,
+    #[serde(default)]
+    pub workers: WorkerConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    /// Rooms by room ID, with the fields inherited from `profiles` and
+    /// `defaults` filled in.
     pub rooms: HashMap<u64, RoomConfig>,
 }
 
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RetryConfig {
+    /// Attempts before an upload is marked as failed, including the first one.
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// Delay in seconds before the first retry, doubled on every attempt.
+    #[serde(default = "default_backoff_base")]
+    pub backoff_base: u64,
+    /// Upper bound in seconds of the delay between two attempts.
+    #[serde(default = "default_backoff_cap")]
+    pub backoff_cap: u64,
+    /// Overrides `max_attempts` for specific classes of errors. Entries of
+    /// the file are merged over the built-in ones.
+    #[serde(
+        default = "default_retry_policy",
+        deserialize_with = "deserialize_retry_policy"
+    )]
+    pub policy: HashMap<ErrorClass, u32>,
+}
+
 fn default_limit() -> usize {
     3
 }
 
-fn default_line() -> String {
-    "AUTO".to_string()
+fn default_line() -> Vec<String> {
+    vec!["AUTO".to_string()]
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct WorkerConfig {
+    /// Uploads running at the same time.
+    #[serde(default = "default_workers")]
+    pub global: usize,
+    /// Uploads running at the same time for one account, unlimited if unset.
+    #[serde(default)]
+    pub per_account: Option<usize>,
+    /// Uploads running at the same time for one room. Only the upload of the
+    /// files runs in parallel, they are submitted one at a time.
+    #[serde(default = "default_workers_per_room")]
+    pub per_room: Option<usize>,
+}
+
+/// Access to the HTTP API. Everything is open while no token is configured.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct AuthConfig {
+    /// Bearer tokens of API clients.
+    #[serde(default)]
+    pub tokens: Vec<TokenConfig>,
+    /// Required by the recorder webhook routes if set. Kept apart from the
+    /// tokens as it ends up in the settings of the recorder.
+    #[serde(default)]
+    pub webhook_secret: Option<String>,
+    /// Addresses allowed to post to the recorder webhook routes. Anyone if
+    /// empty. Matched against the peer address, so a reverse proxy in front
+    /// of the manager has to be listed itself.
+    #[serde(default)]
+    pub webhook_allowlist: Vec<IpNet>,
+    /// Signature required on recorder webhooks, if set.
+    #[serde(default)]
+    pub webhook_hmac: Option<HmacConfig>,
 }
 
+/// Recorder webhooks carry a Unix timestamp in `timestamp_header`, and the
+/// hex HMAC-SHA256 of `<timestamp>.<body>` in `header`, optionally prefixed
+/// with `sha256=`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct HmacConfig {
+    pub secret: String,
+    #[serde(default = "default_hmac_header")]
+    pub header: String,
+    #[serde(default = "default_hmac_timestamp_header")]
+    pub timestamp_header: String,
+    /// Seconds that the timestamp may be off from the local clock.
+    #[serde(default = "default_hmac_max_skew")]
+    pub max_skew: u64,
+}
+
+fn default_hmac_header() -> String {
+    "X-Signature".to_string()
+}
+
+fn default_hmac_timestamp_header() -> String {
+    "X-Timestamp".to_string()
+}
+
+fn default_hmac_max_skew() -> u64 {
+    300
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct TokenConfig {
+    /// Only used in logs.
+    pub name: String,
+    pub token: String,
+    pub role: Role,
+}
+
+fn default_workers() -> usize {
+    1
+}
+
+fn default_workers_per_room() -> Option<usize> {
+    Some(1)
+}
+
+impl Default for WorkerConfig {
+    fn default() -> Self {
+        Self {
+            global: default_workers(),
+            per_account: None,
+            per_room: default_workers_per_room(),
+        }
+    }
+}
+
+fn default_session_gap() -> u64 {
+    600
+}
+
+fn default_review_interval() -> u64 {
+    600
+}
+
+fn default_max_attempts() -> u32 {
+    5
+}
+
+fn default_backoff_base() -> u64 {
+    60
+}
+
+fn default_backoff_cap() -> u64 {
+    3600
+}
+
+fn default_retry_policy() -> HashMap<ErrorClass, u32> {
+    // A broken config or a rejected path does not fix itself, so retrying
+    // is pointless.
+    HashMap::from([(ErrorClass::Config, 1), (ErrorClass::Rejected, 1)])
+}
+
+fn deserialize_retry_policy<'de, D>(deserializer: D) -> Result<HashMap<ErrorClass, u32>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let mut policy = default_retry_policy();
+    policy.extend(HashMap::<ErrorClass, u32>::deserialize(deserializer)?);
+    Ok(policy)
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            backoff_base: default_backoff_base(),
+            backoff_cap: default_backoff_cap(),
+            policy: default_retry_policy(),
+        }
+    }
+}
+
+impl RetryConfig {
+    pub fn max_attempts(&self, class: ErrorClass) -> u32 {
+        *self.policy.get(&class).unwrap_or(&self.max_attempts)
+    }
+
+    /// Delay before the next attempt, given the number of attempts made so far.
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        let exp = attempts.saturating_sub(1).min(31);
+        let secs = self.backoff_base.saturating_mul(1 << exp);
+        Duration::from_secs(secs.min(self.backoff_cap))
+    }
+}
+
+/// The current configuration. Reloads swap it as a whole, so that anything
+/// holding a snapshot keeps a consistent view.
+pub type SharedConfig = ArcSwap<ManagerConfig>;
+
+/// Upload lines known to biliup.
+pub const LINES: &[&str] = &["AUTO", "bda2", "kodo", "ws", "qn", "cos", "cos-internal"];
+
+/// Environment variables starting with this override fields of the config
+/// file, with `__` between nested keys, e.g. `BILIUPMGR__WORKERS__GLOBAL=2`
+/// or `BILIUPMGR__ROOMS__3__STUDIO_TITLE=...`.
+pub const ENV_PREFIX: &str = "BILIUPMGR__";
+
 impl ManagerConfig {
+    /// Read the config file with the overrides from the environment. See
+    /// [`crate::preflight::check`] to find out whether it is usable.
+    ///
+    /// A room may name one of `profiles` in `profile`. Fields the room leaves
+    /// out are taken from that profile, then from `defaults`. Fields are
+    /// taken as a whole, e.g. `tags` of a room replace those of the profile.
     pub fn load(path: &str) -> anyhow::Result<Self> {
         let f = std::fs::File::open(path)?;
-        Ok(serde_yaml::from_reader(std::io::BufReader::new(f))?)
+        let mut value: serde_yaml::Value = serde_yaml::from_reader(std::io::BufReader::new(f))?;
+
+        let version = upgrade::upgrade(&mut value)?;
+        if version < upgrade::CONFIG_VERSION {
+            warn!(
+                "{} is at version {}, run `biliupmgr upgrade-config` to bring it to {}",
+                path,
+                version,
+                upgrade::CONFIG_VERSION
+            );
+        }
+        apply_env(&mut value, std::env::vars());
+        let mut inherited = inherit_rooms(&mut value)?;
+
+        let mut config: Self = serde_yaml::from_value(value)?;
+        for (room_id, room) in &mut config.rooms {
+            room.inherited = inherited.remove(&room_id.to_string()).unwrap_or_default();
+        }
+        Ok(config)
+    }
+
+    /// Catch inconsistencies within the config itself.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.line.is_empty() {
+            bail!("At least one line is required");
+        }
+        for line in &self.line {
+            if !LINES.contains(&line.as_str()) {
+                bail!(
+                    "Unknown line {}, expected one of {}",
+                    line,
+                    LINES.join(", ")
+                );
+            }
+        }
+        if self.workers.global == 0
+            || self.workers.per_account == Some(0)
+            || self.workers.per_room == Some(0)
+        {
+            bail!("Worker limits must be at least 1");
+        }
+        if self.review_interval == 0 {
+            bail!("review_interval must be at least 1");
+        }
+        for (room_id, room) in &self.rooms {
+            if *room_id != room.room_id {
+                bail!("Room {} is listed under {}", room.room_id, room_id);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fill in the fields that rooms leave out. Returns the inherited fields of
+/// each room and their source, by room key.
+fn inherit_rooms(config: &mut Value) -> anyhow::Result<HashMap<String, BTreeMap<String, String>>> {
+    let mut result = HashMap::new();
+    let mapping = match config.as_mapping_mut() {
+        Some(mapping) => mapping,
+        None => return Ok(result),
+    };
+    let defaults = mapping.get(&Value::from("defaults")).cloned();
+    let profiles = mapping.get(&Value::from("profiles")).cloned();
+    let rooms = match mapping
+        .get_mut(&Value::from("rooms"))
+        .and_then(Value::as_mapping_mut)
+    {
+        Some(rooms) => rooms,
+        None => return Ok(result),
+    };
+
+    let room_ids: Vec<Value> = rooms.iter().map(|(room_id, _)| room_id.clone()).collect();
+    for room_id in room_ids {
+        let room = rooms.get_mut(&room_id).unwrap();
+        if room.is_null() {
+            *room = Value::Mapping(Mapping::new());
+        }
+        let room = room
+            .as_mapping_mut()
+            .ok_or_else(|| anyhow!("Room {} must be a mapping", key_name(&room_id)))?;
+
+        let mut layers = Vec::new();
+        match room.get(&Value::from("profile")) {
+            None | Some(Value::Null) => {}
+            Some(Value::String(name)) => {
+                let profile = profiles
+                    .as_ref()
+                    .and_then(|profiles| profiles.get(name.as_str()))
+                    .ok_or_else(|| {
+                        anyhow!("Room {} uses unknown profile {}", key_name(&room_id), name)
+                    })?;
+                layers.push((format!("profiles.{}", name), profile.clone()));
+            }
+            Some(_) => bail!("profile of room {} must be a name", key_name(&room_id)),
+        }
+        if let Some(defaults) = &defaults {
+            layers.push(("defaults".to_string(), defaults.clone()));
+        }
+
+        let mut inherited = BTreeMap::new();
+        for (source, layer) in layers {
+            let layer = match layer {
+                Value::Null => continue,
+                Value::Mapping(layer) => layer,
+                _ => bail!("{} must be a mapping", source),
+            };
+            for (field, value) in layer {
+                let name = key_name(&field);
+                if name == "room_id" || name == "profile" {
+                    bail!("{} cannot set {}", source, name);
+                }
+                if !room.contains_key(&field) {
+                    room.insert(field, value);
+                    inherited.insert(name, source.clone());
+                }
+            }
+        }
+        result.insert(key_name(&room_id), inherited);
+    }
+
+    Ok(result)
+}
+
+fn key_name(key: &Value) -> String {
+    match key {
+        Value::String(s) => s.clone(),
+        Value::Number(n) => n.to_string(),
+        other => format!("{:?}", other),
+    }
+}
+
+fn apply_env(config: &mut Value, vars: impl Iterator<Item = (String, String)>) {
+    for (name, raw) in vars {
+        let path = match name.strip_prefix(ENV_PREFIX) {
+            Some(path) if !path.is_empty() => path.to_lowercase(),
+            _ => continue,
+        };
+
+        // Values are YAML too, so that numbers and lists keep their type.
+        // Mappings are not, as a title like `a: b` is meant as a string.
+        let value = match serde_yaml::from_str(&raw) {
+            Ok(Value::Mapping(_)) | Err(_) => Value::String(raw),
+            Ok(value) => value,
+        };
+        match path.split("__").try_fold(&mut *config, child) {
+            Some(node) => *node = value,
+            None => warn!("Ignored {}, it does not point into a mapping", name),
+        }
+    }
+}
+
+/// The entry `key` of a mapping, created if missing. Keys are compared as
+/// strings, so that `3` finds the room listed under the number 3.
+fn child<'a>(node: &'a mut Value, key: &str) -> Option<&'a mut Value> {
+    if node.is_null() {
+        *node = Value::Mapping(Mapping::new());
+    }
+    let mapping = node.as_mapping_mut()?;
+
+    let existing = mapping.iter().map(|(k, _)| k.clone()).find(|k| match k {
+        Value::String(s) => s == key,
+        Value::Number(n) => n.to_string() == key,
+        _ => false,
+    });
+    let key = existing.unwrap_or_else(|| match key.parse::<u64>() {
+        Ok(n) => Value::Number(n.into()),
+        Err(_) => Value::String(key.to_string()),
+    });
+
+    if !mapping.contains_key(&key) {
+        mapping.insert(key.clone(), Value::Null);
+    }
+    mapping.get_mut(&key)
+}
+
+/// Unix timestamp at which the login in a cookie file written by biliup
+/// expires, i.e. the expiry of its `SESSDATA` cookie.
+pub fn cookie_expiry(path: &str) -> anyhow::Result<i64> {
+    let file = std::fs::File::open(path)?;
+    let cookies: serde_json::Value = serde_json::from_reader(std::io::BufReader::new(file))?;
+
+    let cookies = cookies["cookie_info"]["cookies"]
+        .as_array()
+        .ok_or_else(|| anyhow!("No cookie_info.cookies"))?;
+    for name in ["bili_jct", "DedeUserID"] {
+        if !cookies.iter().any(|cookie| cookie["name"] == name) {
+            bail!("No {} cookie", name);
+        }
+    }
+    cookies
+        .iter()
+        .find(|cookie| cookie["name"] == "SESSDATA")
+        .and_then(|cookie| cookie["expires"].as_i64())
+        .ok_or_else(|| anyhow!("No SESSDATA cookie"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util;
+
+    #[test]
+    fn merges_retry_policy_over_defaults() {
+        let retry: RetryConfig = serde_yaml::from_str("policy: {file: 2, config: 3}").unwrap();
+        assert_eq!(retry.max_attempts(ErrorClass::File), 2);
+        assert_eq!(retry.max_attempts(ErrorClass::Config), 3);
+        assert_eq!(retry.max_attempts(ErrorClass::Rejected), 1);
+        assert_eq!(retry.max_attempts(ErrorClass::Upload), retry.max_attempts);
+    }
+
+    #[test]
+    fn records_inherited_fields() {
+        let path =
+            std::env::temp_dir().join(format!("biliupmgr-{}-config.yaml", std::process::id()));
+        std::fs::write(
+            &path,
+            test_util::config_yaml(
+                "defaults:
+  user_cookie: cookies.json
+  cover: cover.jpg
+  description: ''
+  tags: [tag]
+  tid: 171
+profiles:
+  main:
+    part_title: '%t'
+rooms:
+  3:
+    room_id: 3
+    profile: main
+    studio_title: '%T'
+    inherited:
+      studio_title: forged
+",
+            ),
+        )
+        .unwrap();
+        let config = ManagerConfig::load(path.to_str().unwrap());
+        std::fs::remove_file(&path).unwrap();
+
+        let inherited = &config.unwrap().rooms[&3].inherited;
+        assert_eq!(
+            inherited.get("part_title").map(String::as_str),
+            Some("profiles.main")
+        );
+        assert_eq!(inherited.get("tid").map(String::as_str), Some("defaults"));
+        assert!(!inherited.contains_key("studio_title"));
     }
 }
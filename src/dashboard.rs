@@ -0,0 +1,11 @@
+use actix_web::{get, HttpResponse};
+
+const INDEX: &str = include_str!("../static/index.html");
+
+/// The web dashboard, a single page built on the JSON API.
+#[get("/")]
+pub async fn index() -> HttpResponse {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(INDEX)
+}
@@ -1,12 +1,32 @@
-use sqlx::{self, SqlitePool};
-use anyhow::Result;
-use chrono::{NaiveDateTime, DateTime, Utc};
+use std::fmt;
 
-use crate::{recorder::{RecorderEvent, RecorderEventData}, webhook::{UploadState, UploadHistory}};
+use anyhow::{anyhow, bail, Result};
+use biliup::video::Video;
+use chrono::{DateTime, FixedOffset, NaiveDateTime, Utc};
+use serde::{Deserialize, Serialize};
+use sqlx::{self, Sqlite, SqlitePool, Transaction};
+
+use crate::{
+    recorder::{EventType, RecorderEvent, RecorderEventData, RecorderWebhook, SessionData},
+    webhook::{Paused, UploadHistory, UploadState, UploadTransition},
+};
+
+/// Selects uploads for the history. Unset fields match everything.
+#[derive(Debug, Default)]
+pub(crate) struct HistoryFilter {
+    pub(crate) room_id: Option<u64>,
+    pub(crate) from: Option<DateTime<Utc>>,
+    pub(crate) to: Option<DateTime<Utc>>,
+    pub(crate) status: Option<UploadStatus>,
+    /// Matched against the stream, archive and part titles.
+    pub(crate) search: Option<String>,
+    pub(crate) limit: i64,
+    pub(crate) offset: i64,
+}
 
 #[derive(Clone)]
 pub struct BiliupDao {
-    pool: SqlitePool
+    pool: SqlitePool,
 }
 
 impl BiliupDao {
@@ -17,21 +37,115 @@ impl BiliupDao {
 
 // Table `events`
 impl BiliupDao {
-    pub async fn add_event(&self, event: &RecorderEvent) -> Result<()> {
-        let mut conn = self.pool.acquire().await?;
+    /// Store a recorded file and queue its upload in one transaction.
+    ///
+    /// Redelivered events are not stored twice: the status of the existing
+    /// upload is returned instead.
+    pub async fn add_event(&self, event: &RecorderEvent) -> Result<Option<UploadStatus>> {
+        let mut tx = self.pool.begin().await?;
 
         let room_id = event.event_data.room_id as i64;
         let file_size = event.event_data.file_size as i64;
-        let file_open_time = chrono::DateTime::parse_from_rfc3339(&event.event_data.file_open_time).unwrap();
+        let file_open_time =
+            chrono::DateTime::parse_from_rfc3339(&event.event_data.file_open_time)?;
+        let event_type = event.event_type.as_str();
         sqlx::query!(
             "
-            INSERT INTO events (event_type, event_id, room_id, name, title, relative_path, file_size, duration, file_open_time)
-            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+            INSERT INTO events (event_type, event_id, room_id, name, title, relative_path, file_size, duration, file_open_time, session_id)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+            ON CONFLICT (event_id) DO NOTHING
             ",
-            event.event_type, event.event_id, room_id,
-            event.event_data.name, event.event_data.title, 
-            event.event_data.relative_path, file_size, 
-            event.event_data.duration, file_open_time
+            event_type, event.event_id, room_id,
+            event.event_data.name, event.event_data.title,
+            event.event_data.relative_path, file_size,
+            event.event_data.duration, file_open_time,
+            event.event_data.session_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        let status = sqlx::query_scalar!(
+            r#"
+            SELECT status AS "status: UploadStatus"
+            FROM uploads
+            WHERE event_id = ?1
+            "#,
+            event.event_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+        if status.is_some() {
+            return Ok(status);
+        }
+
+        add_upload(&mut tx, &event.event_id).await?;
+        tx.commit().await?;
+
+        Ok(None)
+    }
+
+    pub async fn get_event(&self, event_id: &str) -> Result<Option<RecorderEvent>> {
+        let event_row = sqlx::query_as!(
+            EventRow,
+            r#"
+            SELECT event_id, event_type AS "event_type: EventType", room_id, name, title, relative_path,
+                file_open_time AS "file_open_time: DateTime<FixedOffset>", file_size, duration, session_id
+            FROM events
+            WHERE event_id = ?1
+            "#,
+            event_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(event_row.map(RecorderEvent::from))
+    }
+}
+
+// Tables `recorder_events` and `sessions`
+impl BiliupDao {
+    /// Keep a log of every event posted by the recorder.
+    /// Returns `false` if the event was delivered before.
+    pub async fn add_recorder_event(&self, webhook: &RecorderWebhook) -> Result<bool> {
+        let mut conn = self.pool.acquire().await?;
+
+        let event_type = webhook.payload.event_type().as_str();
+        let room_id = webhook.payload.room_id() as i64;
+        let session_id = webhook.payload.session_id();
+        let payload = serde_json::to_string(&webhook.payload)?;
+        let now = chrono::Utc::now();
+        let inserted = sqlx::query!(
+            "
+            INSERT INTO recorder_events (event_id, event_type, room_id, session_id, payload, received_at)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6)
+            ON CONFLICT (event_id) DO NOTHING
+            ",
+            webhook.event_id, event_type, room_id, session_id, payload, now
+        )
+        .execute(&mut conn)
+        .await?
+        .rows_affected();
+
+        Ok(inserted > 0)
+    }
+
+    /// `started_at` is when the recorder saw the session start. A session
+    /// keeps the time it was first started at.
+    pub async fn start_session(&self, data: &SessionData, started_at: DateTime<Utc>) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+
+        let room_id = data.room.room_id as i64;
+        sqlx::query!(
+            "
+            INSERT INTO sessions (session_id, room_id, name, title, started_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (session_id) DO UPDATE SET started_at = COALESCE(started_at, excluded.started_at)
+            ",
+            data.session_id,
+            room_id,
+            data.room.name,
+            data.room.title,
+            started_at
         )
         .execute(&mut conn)
         .await?;
@@ -39,125 +153,914 @@ impl BiliupDao {
         Ok(())
     }
 
-    pub async fn get_event(&self, event_id: &str) -> Result<Option<RecorderEvent>> {
-        struct _EventRow {
-            event_id: String,
-            event_type: String,
-            room_id: i64,
+    /// The most recent session of a room that has not ended yet.
+    pub async fn current_session(&self, room_id: u64) -> Result<Option<(String, String, String)>> {
+        let room_id = room_id as i64;
+
+        struct _Session {
+            session_id: String,
             name: String,
             title: String,
-            relative_path: String,
-            file_open_time: NaiveDateTime,
-            file_size: i64,
-            duration: f32,
         }
-
-        let event_row = sqlx::query_as!(
-            _EventRow,
+        let session = sqlx::query_as!(
+            _Session,
             "
-            SELECT event_id, event_type, room_id, name, title, relative_path, file_open_time, file_size, duration
-            FROM events
-            WHERE event_id = ?1
+            SELECT session_id, name, title
+            FROM sessions
+            WHERE room_id = ?1 AND ended_at IS NULL
+            ORDER BY started_at DESC
+            LIMIT 1
             ",
-            event_id
+            room_id
         )
         .fetch_optional(&self.pool)
         .await?;
 
-        let event = match event_row {
-            Some(event_row) => {
-                let file_open_time = DateTime::<Utc>::from_utc(event_row.file_open_time, Utc);
-                Some(RecorderEvent {
-                    event_id: event_row.event_id,
-                    event_type: event_row.event_type,
-                    event_data: RecorderEventData {
-                        room_id: event_row.room_id as u64,
-                        name: event_row.name,
-                        title: event_row.title,
-                        relative_path: event_row.relative_path,
-                        file_open_time: file_open_time.to_rfc3339(),
-                        file_size: event_row.file_size as u64,
-                        duration: event_row.duration as f64
-                    }
-                })},
-            None => None,
-        };
+        Ok(session.map(|s| (s.session_id, s.name, s.title)))
+    }
+
+    pub async fn end_session(&self, data: &SessionData, ended_at: DateTime<Utc>) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
 
-        Ok(event)
+        let room_id = data.room.room_id as i64;
+        sqlx::query!(
+            "
+            INSERT INTO sessions (session_id, room_id, name, title, ended_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (session_id) DO UPDATE SET title = excluded.title, ended_at = excluded.ended_at
+            ",
+            data.session_id, room_id, data.room.name, data.room.title, ended_at
+        )
+        .execute(&mut conn)
+        .await?;
+
+        Ok(())
     }
 }
 
-// Table `uploads`
+// Table `paused`, where room 0 is the whole queue
 impl BiliupDao {
-    pub async fn add_upload(&self, event: &RecorderEvent) -> Result<u64> {
+    pub(crate) async fn get_paused(&self) -> Result<Paused> {
+        let room_ids = sqlx::query_scalar!(
+            "
+            SELECT room_id
+            FROM paused
+            "
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut paused = Paused::default();
+        for room_id in room_ids {
+            match room_id {
+                0 => paused.all = true,
+                room_id => {
+                    paused.rooms.insert(room_id as u64);
+                }
+            }
+        }
+
+        Ok(paused)
+    }
+
+    /// Pause a room, or the whole queue if `None`.
+    pub(crate) async fn pause(&self, room_id: Option<u64>) -> Result<()> {
         let mut conn = self.pool.acquire().await?;
 
-        let now = chrono::Utc::now();
-        let id = sqlx::query!(
+        let room_id = room_id.unwrap_or(0) as i64;
+        sqlx::query!(
             "
-            INSERT INTO uploads (event_id, created_at)
-            VALUES (?1, ?2)
+            INSERT INTO paused (room_id)
+            VALUES (?1)
+            ON CONFLICT (room_id) DO NOTHING
             ",
-            event.event_id, now
+            room_id
         )
         .execute(&mut conn)
-        .await?
-        .last_insert_rowid();
+        .await?;
 
-        Ok(id as u64)
+        Ok(())
     }
 
-    pub async fn finish_upload(&self, event_id: &str, aid: u64, title: &str) -> Result<()> {
+    /// Resume a room, or the whole queue and every room if `None`.
+    pub(crate) async fn resume(&self, room_id: Option<u64>) -> Result<()> {
         let mut conn = self.pool.acquire().await?;
 
+        let room_id = room_id.map(|room_id| room_id as i64);
+        sqlx::query!(
+            "
+            DELETE FROM paused
+            WHERE ?1 IS NULL OR room_id = ?1
+            ",
+            room_id
+        )
+        .execute(&mut conn)
+        .await?;
+
+        Ok(())
+    }
+}
+
+struct EventRow {
+    event_id: String,
+    event_type: EventType,
+    room_id: i64,
+    name: String,
+    title: String,
+    relative_path: String,
+    file_open_time: DateTime<FixedOffset>,
+    file_size: i64,
+    duration: f32,
+    session_id: Option<String>,
+}
+
+impl From<EventRow> for RecorderEvent {
+    fn from(event_row: EventRow) -> Self {
+        RecorderEvent {
+            event_id: event_row.event_id,
+            event_type: event_row.event_type,
+            event_data: RecorderEventData {
+                room_id: event_row.room_id as u64,
+                name: event_row.name,
+                title: event_row.title,
+                relative_path: event_row.relative_path,
+                file_open_time: event_row.file_open_time.to_rfc3339(),
+                file_size: event_row.file_size as u64,
+                duration: event_row.duration as f64,
+                duration_unknown: false,
+                session_id: event_row.session_id,
+            },
+        }
+    }
+}
+
+/// Lifecycle of an upload job.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+#[serde(rename_all = "snake_case")]
+#[sqlx(rename_all = "snake_case")]
+pub enum UploadStatus {
+    /// Waiting for a worker, possibly postponed until `next_attempt_at`.
+    Queued,
+    /// The recording is not on disk yet.
+    WaitingForFile,
+    Uploading,
+    /// The video file is on the server but the archive is not submitted yet.
+    Uploaded,
+    Submitting,
+    /// The archive is submitted, its review has not been checked yet.
+    Submitted,
+    UnderReview,
+    Published,
+    /// The archive did not pass review. It has to be fixed on bilibili.
+    Rejected,
+    /// Gave up after running out of attempts.
+    Failed,
+    Cancelled,
+}
+
+impl UploadStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Queued => "queued",
+            Self::WaitingForFile => "waiting_for_file",
+            Self::Uploading => "uploading",
+            Self::Uploaded => "uploaded",
+            Self::Submitting => "submitting",
+            Self::Submitted => "submitted",
+            Self::UnderReview => "under_review",
+            Self::Published => "published",
+            Self::Rejected => "rejected",
+            Self::Failed => "failed",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    pub fn can_transition_to(&self, next: Self) -> bool {
+        use UploadStatus::*;
+
+        match self {
+            // Straight to `Uploaded` when resuming with the file already on the server.
+            Queued | WaitingForFile => matches!(
+                next,
+                Queued | WaitingForFile | Uploading | Uploaded | Failed | Cancelled
+            ),
+            Uploading => matches!(
+                next,
+                Uploaded | Queued | WaitingForFile | Failed | Cancelled
+            ),
+            Uploaded => matches!(next, Submitting | Queued | Failed | Cancelled),
+            Submitting => matches!(next, Submitted | Uploaded | Queued | Failed),
+            Submitted => matches!(next, UnderReview | Published | Rejected),
+            UnderReview => matches!(next, Published | Rejected),
+            Published | Rejected => false,
+            Failed => matches!(next, Queued | Cancelled),
+            Cancelled => matches!(next, Queued),
+        }
+    }
+}
+
+impl fmt::Display for UploadStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Move an upload to `to`, recording when it happened.
+async fn transition(
+    tx: &mut Transaction<'_, Sqlite>,
+    event_id: &str,
+    to: UploadStatus,
+) -> Result<()> {
+    let from = sqlx::query_scalar!(
+        r#"
+        SELECT status AS "status: UploadStatus"
+        FROM uploads
+        WHERE event_id = ?1
+        "#,
+        event_id
+    )
+    .fetch_optional(&mut *tx)
+    .await?
+    .ok_or_else(|| anyhow!("No upload for event {}", event_id))?;
+
+    if from == to {
+        return Ok(());
+    }
+    if !from.can_transition_to(to) {
+        bail!("Invalid transition of {}: {} -> {}", event_id, from, to);
+    }
+
+    let now = chrono::Utc::now();
+    let status = to.as_str();
+    sqlx::query!(
+        "
+        UPDATE uploads
+        SET status = ?1, updated_at = ?2
+        WHERE event_id = ?3
+        ",
+        status,
+        now,
+        event_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query!(
+        "
+        INSERT INTO upload_transitions (event_id, status, created_at)
+        VALUES (?1, ?2, ?3)
+        ",
+        event_id,
+        status,
+        now
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+async fn add_upload(tx: &mut Transaction<'_, Sqlite>, event_id: &str) -> Result<u64> {
+    let now = chrono::Utc::now();
+    let status = UploadStatus::Queued.as_str();
+    let id = sqlx::query!(
+        "
+        INSERT INTO uploads (event_id, status, created_at, updated_at)
+        VALUES (?1, ?2, ?3, ?3)
+        ",
+        event_id,
+        status,
+        now
+    )
+    .execute(&mut *tx)
+    .await?
+    .last_insert_rowid();
+
+    sqlx::query!(
+        "
+        INSERT INTO upload_transitions (event_id, status, created_at)
+        VALUES (?1, ?2, ?3)
+        ",
+        event_id,
+        status,
+        now
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(id as u64)
+}
+
+async fn requeue(tx: &mut Transaction<'_, Sqlite>, event_id: &str, priority: i64) -> Result<()> {
+    transition(&mut *tx, event_id, UploadStatus::Queued).await?;
+
+    sqlx::query!(
+        "
+        UPDATE uploads
+        SET next_attempt_at = NULL, attempts = 0, priority = ?1
+        WHERE event_id = ?2
+        ",
+        priority,
+        event_id
+    )
+    .execute(&mut *tx)
+    .await?;
+
+    Ok(())
+}
+
+/// A submitted upload whose archive is in review.
+pub struct PendingReview {
+    pub event_id: String,
+    pub room_id: u64,
+    pub aid: u64,
+    pub status: UploadStatus,
+}
+
+/// An upload waiting for a worker.
+pub struct QueuedUpload {
+    pub event: RecorderEvent,
+    /// Higher goes first.
+    pub priority: i64,
+    /// When the upload was queued, or became due after a retry delay.
+    pub queued_since: NaiveDateTime,
+}
+
+// Table `uploads`
+impl BiliupDao {
+    pub async fn set_status(&self, event_id: &str, status: UploadStatus) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+        transition(&mut tx, event_id, status).await?;
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_status(&self, event_id: &str) -> Result<Option<UploadStatus>> {
+        let status = sqlx::query_scalar!(
+            r#"
+            SELECT status AS "status: UploadStatus"
+            FROM uploads
+            WHERE event_id = ?1
+            "#,
+            event_id
+        )
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(status)
+    }
+
+    /// Parts appended to an archive take the BV ID of the archive from the
+    /// parts submitted before them.
+    pub async fn finish_upload(
+        &self,
+        event_id: &str,
+        aid: u64,
+        bvid: Option<&str>,
+        title: &str,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        transition(&mut tx, event_id, UploadStatus::Submitted).await?;
+
         let aid = aid as i64;
         let now = chrono::Utc::now();
         sqlx::query!(
             "
             UPDATE uploads
-            SET uploaded = 1, finished_at = ?1, avid = ?2, archive = ?3
-            WHERE event_id = ?4
+            SET finished_at = ?1, avid = ?2, archive = ?3,
+                bvid = COALESCE(?4, (SELECT bvid FROM uploads WHERE avid = ?2 AND bvid IS NOT NULL LIMIT 1))
+            WHERE event_id = ?5
             ",
-            now, aid, title, event_id
+            now, aid, title, bvid, event_id
         )
-        .execute(&mut conn)
+        .execute(&mut tx)
         .await?;
 
+        tx.commit().await?;
+
         Ok(())
     }
 
-    pub async fn find_existing_upload(&self, data: &RecorderEventData) -> Result<Option<u64>> {
-        let room_id = data.room_id as i64;
+    /// Remember the archive as soon as it is submitted, so that an attempt
+    /// interrupted before [`Self::finish_upload`] does not submit it again.
+    pub async fn set_submitted_archive(
+        &self,
+        event_id: &str,
+        aid: u64,
+        bvid: Option<&str>,
+    ) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
 
-        struct _Upload { avid: Option<i64> }
-        let aid = match sqlx::query_as!(
-            _Upload,
+        let aid = aid as i64;
+        sqlx::query!(
+            "
+            UPDATE uploads
+            SET avid = ?1, bvid = ?2
+            WHERE event_id = ?3
+            ",
+            aid,
+            bvid,
+            event_id
+        )
+        .execute(&mut conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// The archive an earlier attempt submitted or appended the upload to.
+    pub async fn get_submitted_archive(&self, event_id: &str) -> Result<Option<u64>> {
+        let aid = sqlx::query_scalar!(
             "
             SELECT avid
             FROM uploads
-            JOIN events ON events.event_id = uploads.event_id
-            WHERE room_id = ?1 AND file_open_time = ?2 AND uploaded = 1 AND avid IS NOT NULL
+            WHERE event_id = ?1
+            ",
+            event_id
+        )
+        .fetch_optional(&self.pool)
+        .await?
+        .flatten();
+
+        Ok(aid.map(|aid| aid as u64))
+    }
+
+    /// The video file already on the server, if the upload got that far.
+    pub async fn get_uploaded_video(&self, event_id: &str) -> Result<Option<Video>> {
+        let video = sqlx::query_scalar!(
+            "
+            SELECT video
+            FROM uploads
+            WHERE event_id = ?1
             ",
-            room_id, data.file_open_time
+            event_id
         )
         .fetch_optional(&self.pool)
-        .await? {
-            Some(upload) => upload.avid.unwrap(),
-            None => return Ok(None)
+        .await?
+        .flatten();
+
+        match video {
+            Some(video) => Ok(Some(serde_json::from_str(&video)?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set_uploaded_video(&self, event_id: &str, video: &Video) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+
+        let part_title = video.title.as_deref();
+        let video = serde_json::to_string(video)?;
+        sqlx::query!(
+            "
+            UPDATE uploads
+            SET video = ?1, part_title = ?2
+            WHERE event_id = ?3
+            ",
+            video,
+            part_title,
+            event_id
+        )
+        .execute(&mut conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Record a failed attempt and return the number of attempts made so far.
+    pub async fn add_attempt(&self, event_id: &str, error: &str) -> Result<u32> {
+        let mut tx = self.pool.begin().await?;
+
+        sqlx::query!(
+            "
+            UPDATE uploads
+            SET attempts = attempts + 1, last_error = ?1
+            WHERE event_id = ?2
+            ",
+            error,
+            event_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        let attempts = sqlx::query_scalar!(
+            "
+            SELECT attempts
+            FROM uploads
+            WHERE event_id = ?1
+            ",
+            event_id
+        )
+        .fetch_one(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(attempts as u32)
+    }
+
+    /// Postpone an upload, leaving it either queued or waiting for its file.
+    pub async fn schedule_retry(
+        &self,
+        event_id: &str,
+        status: UploadStatus,
+        next_attempt_at: DateTime<Utc>,
+    ) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        transition(&mut tx, event_id, status).await?;
+
+        sqlx::query!(
+            "
+            UPDATE uploads
+            SET next_attempt_at = ?1
+            WHERE event_id = ?2
+            ",
+            next_attempt_at,
+            event_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn fail_upload(&self, event_id: &str) -> Result<()> {
+        let mut tx = self.pool.begin().await?;
+
+        transition(&mut tx, event_id, UploadStatus::Failed).await?;
+
+        sqlx::query!(
+            "
+            UPDATE uploads
+            SET next_attempt_at = NULL
+            WHERE event_id = ?1
+            ",
+            event_id
+        )
+        .execute(&mut tx)
+        .await?;
+
+        tx.commit().await?;
+
+        Ok(())
+    }
+
+    /// Put a failed or cancelled upload back into the queue with a fresh
+    /// retry budget. Returns `false` for uploads in any other state.
+    pub async fn requeue_upload(&self, event_id: &str, priority: i64) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+
+        let status = sqlx::query_scalar!(
+            r#"
+            SELECT status AS "status: UploadStatus"
+            FROM uploads
+            WHERE event_id = ?1
+            "#,
+            event_id
+        )
+        .fetch_optional(&mut tx)
+        .await?;
+
+        match status {
+            Some(UploadStatus::Failed | UploadStatus::Cancelled) => (),
+            _ => return Ok(false),
+        }
+
+        requeue(&mut tx, event_id, priority).await?;
+        tx.commit().await?;
+
+        Ok(true)
+    }
+
+    /// Put failed uploads back into the queue, optionally only those of a
+    /// room or created within `[from, to)`. Returns the requeued event IDs.
+    pub async fn requeue_failed_uploads(
+        &self,
+        room_id: Option<u64>,
+        from: Option<DateTime<Utc>>,
+        to: Option<DateTime<Utc>>,
+        priority: i64,
+    ) -> Result<Vec<String>> {
+        let mut tx = self.pool.begin().await?;
+
+        let room_id = room_id.map(|room_id| room_id as i64);
+        let event_ids = sqlx::query_scalar!(
+            "
+            SELECT uploads.event_id
+            FROM uploads
+            JOIN events ON events.event_id = uploads.event_id
+            WHERE status = 'failed'
+                AND (?1 IS NULL OR room_id = ?1)
+                AND (?2 IS NULL OR uploads.created_at >= ?2)
+                AND (?3 IS NULL OR uploads.created_at < ?3)
+            ORDER BY uploads.id
+            ",
+            room_id,
+            from,
+            to
+        )
+        .fetch_all(&mut tx)
+        .await?;
+
+        for event_id in &event_ids {
+            requeue(&mut tx, event_id, priority).await?;
+        }
+        tx.commit().await?;
+
+        Ok(event_ids)
+    }
+
+    pub async fn set_priority(&self, event_id: &str, priority: i64) -> Result<()> {
+        let mut conn = self.pool.acquire().await?;
+
+        sqlx::query!(
+            "
+            UPDATE uploads
+            SET priority = ?1
+            WHERE event_id = ?2
+            ",
+            priority,
+            event_id
+        )
+        .execute(&mut conn)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Put uploads interrupted by a restart back into the queue. Failed ones
+    /// stay failed until retried through the API, and queued or waiting ones
+    /// keep their schedule.
+    pub async fn requeue_unfinished_uploads(&self) -> Result<u64> {
+        let mut tx = self.pool.begin().await?;
+
+        let now = chrono::Utc::now();
+        sqlx::query!(
+            "
+            INSERT INTO upload_transitions (event_id, status, created_at)
+            SELECT event_id, 'queued', ?1
+            FROM uploads
+            WHERE status IN ('uploading', 'uploaded', 'submitting')
+            ",
+            now
+        )
+        .execute(&mut tx)
+        .await?;
+
+        let affected = sqlx::query!(
+            "
+            UPDATE uploads
+            SET status = 'queued', updated_at = ?1, next_attempt_at = NULL
+            WHERE status IN ('uploading', 'uploaded', 'submitting')
+            ",
+            now
+        )
+        .execute(&mut tx)
+        .await?
+        .rows_affected();
+
+        tx.commit().await?;
+
+        Ok(affected)
+    }
+
+    /// When the earliest postponed upload becomes due.
+    pub async fn next_attempt_at(&self) -> Result<Option<DateTime<Utc>>> {
+        let now = chrono::Utc::now();
+        let next_attempt_at = sqlx::query_scalar!(
+            r#"
+            SELECT MIN(next_attempt_at) AS "next_attempt_at: NaiveDateTime"
+            FROM uploads
+            WHERE status IN ('queued', 'waiting_for_file') AND next_attempt_at > ?1
+            "#,
+            now
+        )
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(next_attempt_at.map(|dt| DateTime::<Utc>::from_utc(dt, Utc)))
+    }
+
+    /// Queued uploads that are not postponed, by priority then oldest first.
+    pub async fn queued_uploads(&self) -> Result<Vec<QueuedUpload>> {
+        struct _QueuedRow {
+            event_id: String,
+            event_type: EventType,
+            room_id: i64,
+            name: String,
+            title: String,
+            relative_path: String,
+            file_open_time: DateTime<FixedOffset>,
+            file_size: i64,
+            duration: f32,
+            session_id: Option<String>,
+            priority: i64,
+            queued_since: NaiveDateTime,
+        }
+
+        let now = chrono::Utc::now();
+        let rows = sqlx::query_as!(
+            _QueuedRow,
+            r#"
+            SELECT events.event_id, event_type AS "event_type: EventType", room_id, name, title, relative_path,
+                file_open_time AS "file_open_time: DateTime<FixedOffset>", file_size, duration, session_id,
+                uploads.priority,
+                MAX(uploads.updated_at, COALESCE(next_attempt_at, uploads.updated_at)) AS "queued_since!: NaiveDateTime"
+            FROM uploads
+            JOIN events ON events.event_id = uploads.event_id
+            WHERE status IN ('queued', 'waiting_for_file') AND (next_attempt_at IS NULL OR next_attempt_at <= ?1)
+            ORDER BY uploads.priority DESC, uploads.id
+            "#,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let uploads = rows
+            .into_iter()
+            .map(|row| QueuedUpload {
+                priority: row.priority,
+                queued_since: row.queued_since,
+                event: RecorderEvent::from(EventRow {
+                    event_id: row.event_id,
+                    event_type: row.event_type,
+                    room_id: row.room_id,
+                    name: row.name,
+                    title: row.title,
+                    relative_path: row.relative_path,
+                    file_open_time: row.file_open_time,
+                    file_size: row.file_size,
+                    duration: row.duration,
+                    session_id: row.session_id,
+                }),
+            })
+            .collect();
+
+        Ok(uploads)
+    }
+
+    pub(crate) async fn get_transitions(&self, event_id: &str) -> Result<Vec<UploadTransition>> {
+        let transitions = sqlx::query_as!(
+            UploadTransition,
+            r#"
+            SELECT status AS "status: UploadStatus", created_at
+            FROM upload_transitions
+            WHERE event_id = ?1
+            ORDER BY id
+            "#,
+            event_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(transitions)
+    }
+
+    /// Find the archive that a recording belongs to.
+    ///
+    /// Parts of the same live session share an archive. Otherwise, parts of
+    /// a room closer than `gap` to each other are considered the same
+    /// session, unless the earlier session is known to have ended.
+    pub async fn find_existing_upload(
+        &self,
+        data: &RecorderEventData,
+        gap: chrono::Duration,
+    ) -> Result<Option<u64>> {
+        let room_id = data.room_id as i64;
+
+        struct _Upload {
+            avid: Option<i64>,
+            session_id: Option<String>,
+            session_ended_at: Option<NaiveDateTime>,
+            file_open_time: DateTime<FixedOffset>,
+            duration: f32,
+        }
+        let uploads = sqlx::query_as!(
+            _Upload,
+            r#"
+            SELECT avid, events.session_id,
+                (SELECT ended_at FROM sessions WHERE sessions.session_id = events.session_id)
+                    AS "session_ended_at?: NaiveDateTime",
+                file_open_time AS "file_open_time: DateTime<FixedOffset>", duration
+            FROM uploads
+            JOIN events ON events.event_id = uploads.event_id
+            WHERE events.room_id = ?1 AND avid IS NOT NULL
+                AND status IN ('submitted', 'under_review', 'published')
+            ORDER BY uploads.id DESC
+            LIMIT 32
+            "#,
+            room_id
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        if let Some(session_id) = &data.session_id {
+            let same_session = uploads
+                .iter()
+                .find(|upload| upload.session_id.as_ref() == Some(session_id));
+            if let Some(upload) = same_session {
+                return Ok(upload.avid.map(|aid| aid as u64));
+            }
+        }
+
+        let (start, end) = match data.time_range() {
+            Some(range) => range,
+            None => return Ok(None),
         };
-        
-        Ok(Some(aid as u64))
+        // An ended session means the stream is over, so its archive is final.
+        let adjacent = uploads
+            .iter()
+            .filter(|upload| upload.session_ended_at.is_none())
+            .find(|upload| {
+                let upload_start = upload.file_open_time;
+                let upload_end = upload_start
+                    + chrono::Duration::milliseconds((upload.duration * 1000.0) as i64);
+                start - upload_end <= gap && upload_start - end <= gap
+            });
+
+        Ok(adjacent
+            .and_then(|upload| upload.avid)
+            .map(|aid| aid as u64))
+    }
+
+    /// Videos this manager added to an archive, with when they were recorded.
+    pub async fn get_archive_parts(&self, aid: u64) -> Result<Vec<(Video, DateTime<FixedOffset>)>> {
+        let aid = aid as i64;
+
+        struct _Part {
+            video: Option<String>,
+            file_open_time: DateTime<FixedOffset>,
+        }
+        let parts = sqlx::query_as!(
+            _Part,
+            r#"
+            SELECT uploads.video, events.file_open_time AS "file_open_time: DateTime<FixedOffset>"
+            FROM uploads
+            JOIN events ON events.event_id = uploads.event_id
+            WHERE avid = ?1 AND video IS NOT NULL
+            "#,
+            aid
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut result = Vec::new();
+        for part in parts {
+            if let Some(video) = part.video {
+                result.push((serde_json::from_str(&video)?, part.file_open_time));
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Submitted uploads whose archive is not reviewed yet.
+    pub async fn pending_reviews(&self) -> Result<Vec<PendingReview>> {
+        struct _Pending {
+            event_id: String,
+            room_id: i64,
+            avid: i64,
+            status: UploadStatus,
+        }
+        let rows = sqlx::query_as!(
+            _Pending,
+            r#"
+            SELECT uploads.event_id, events.room_id, avid AS "avid!", status AS "status: UploadStatus"
+            FROM uploads
+            JOIN events ON events.event_id = uploads.event_id
+            WHERE status IN ('submitted', 'under_review') AND avid IS NOT NULL
+            ORDER BY uploads.id
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(rows
+            .into_iter()
+            .map(|row| PendingReview {
+                event_id: row.event_id,
+                room_id: row.room_id as u64,
+                aid: row.avid as u64,
+                status: row.status,
+            })
+            .collect())
     }
 
     pub(crate) async fn get_unfinished_uploads(&self) -> Result<Vec<UploadState>> {
         let uploads = sqlx::query_as!(
             UploadState,
-            "
-            SELECT uploads.event_id, uploads.created_at, events.relative_path, events.file_size
+            r#"
+            SELECT uploads.event_id, uploads.status AS "status: UploadStatus", uploads.created_at,
+                uploads.updated_at, events.relative_path, events.file_size, uploads.attempts,
+                uploads.last_error, uploads.next_attempt_at, uploads.priority
             FROM uploads
             JOIN events ON events.event_id = uploads.event_id
-            WHERE uploaded = 0
-            "
+            WHERE status NOT IN ('submitted', 'under_review', 'published', 'rejected', 'cancelled')
+            "#
         )
         .fetch_all(&self.pool)
         .await?;
@@ -165,19 +1068,92 @@ impl BiliupDao {
         Ok(uploads)
     }
 
-    pub(crate) async fn get_finished_uploads(&self) -> Result<Vec<UploadHistory>> {
+    pub(crate) async fn count_uploads_by_status(&self) -> Result<Vec<(UploadStatus, i64)>> {
+        let counts = sqlx::query!(
+            r#"
+            SELECT status AS "status: UploadStatus", COUNT(*) AS "count!: i64"
+            FROM uploads
+            GROUP BY status
+            "#
+        )
+        .fetch_all(&self.pool)
+        .await?;
+
+        Ok(counts
+            .into_iter()
+            .map(|row| (row.status, row.count))
+            .collect())
+    }
+
+    /// Uploads matching `filter`, newest first, and how many match in total.
+    pub(crate) async fn get_upload_history(
+        &self,
+        filter: &HistoryFilter,
+    ) -> Result<(i64, Vec<UploadHistory>)> {
+        let mut conn = self.pool.acquire().await?;
+
+        let room_id = filter.room_id.map(|room_id| room_id as i64);
+        let status = filter.status.map(|status| status.as_str());
+        let search = filter.search.as_deref().map(|search| {
+            let escaped = search
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_");
+            format!("%{}%", escaped)
+        });
+
+        let total = sqlx::query_scalar!(
+            r#"
+            SELECT COUNT(*) AS "count!: i64"
+            FROM uploads
+            JOIN events ON events.event_id = uploads.event_id
+            WHERE (?1 IS NULL OR events.room_id = ?1)
+                AND (?2 IS NULL OR uploads.created_at >= ?2)
+                AND (?3 IS NULL OR uploads.created_at < ?3)
+                AND (?4 IS NULL OR uploads.status = ?4)
+                AND (?5 IS NULL OR events.title LIKE ?5 ESCAPE '\'
+                    OR uploads.archive LIKE ?5 ESCAPE '\'
+                    OR uploads.part_title LIKE ?5 ESCAPE '\')
+            "#,
+            room_id,
+            filter.from,
+            filter.to,
+            status,
+            search
+        )
+        .fetch_one(&mut conn)
+        .await?;
+
         let uploads = sqlx::query_as!(
             UploadHistory,
-            "
-            SELECT uploads.finished_at, events.relative_path, events.file_size, uploads.avid
+            r#"
+            SELECT uploads.event_id, events.room_id, events.name, events.title,
+                uploads.status AS "status: UploadStatus", uploads.created_at, uploads.finished_at,
+                events.relative_path, events.file_size, events.duration,
+                uploads.avid, uploads.bvid, uploads.archive AS archive_title, uploads.part_title
             FROM uploads
             JOIN events ON events.event_id = uploads.event_id
-            WHERE uploaded = 1 AND uploads.created_at >= DATE('now', '-1 day')
-            "
+            WHERE (?1 IS NULL OR events.room_id = ?1)
+                AND (?2 IS NULL OR uploads.created_at >= ?2)
+                AND (?3 IS NULL OR uploads.created_at < ?3)
+                AND (?4 IS NULL OR uploads.status = ?4)
+                AND (?5 IS NULL OR events.title LIKE ?5 ESCAPE '\'
+                    OR uploads.archive LIKE ?5 ESCAPE '\'
+                    OR uploads.part_title LIKE ?5 ESCAPE '\')
+            ORDER BY uploads.id DESC
+            LIMIT ?6 OFFSET ?7
+            "#,
+            room_id,
+            filter.from,
+            filter.to,
+            status,
+            search,
+            filter.limit,
+            filter.offset
         )
-        .fetch_all(&self.pool)
+        .fetch_all(&mut conn)
         .await?;
 
-        Ok(uploads)
+        Ok((total, uploads))
     }
 }
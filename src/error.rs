@@ -0,0 +1,168 @@
+use std::fmt;
+
+use actix_web::{http::StatusCode, web, HttpResponse, ResponseError};
+use log::warn;
+use serde::Serialize;
+
+/// An error returned by the HTTP API as
+/// `{"error": {"code": "...", "message": "..."}}`.
+#[derive(Debug)]
+pub struct ApiError {
+    status: StatusCode,
+    /// Machine-readable, stable across releases.
+    code: &'static str,
+    message: String,
+}
+
+pub type ApiResult<T> = Result<T, ApiError>;
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    code: &'a str,
+    message: &'a str,
+}
+
+impl ApiError {
+    pub fn new(status: StatusCode, code: &'static str, message: impl Into<String>) -> Self {
+        Self {
+            status,
+            code,
+            message: message.into(),
+        }
+    }
+
+    pub fn bad_request(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, code, message)
+    }
+
+    pub fn unauthorized(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNAUTHORIZED, code, message)
+    }
+
+    pub fn forbidden(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::FORBIDDEN, code, message)
+    }
+
+    pub fn not_found(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::NOT_FOUND, code, message)
+    }
+
+    pub fn unprocessable(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::UNPROCESSABLE_ENTITY, code, message)
+    }
+
+    pub fn conflict(code: &'static str, message: impl Into<String>) -> Self {
+        Self::new(StatusCode::CONFLICT, code, message)
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        self.status
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status);
+        if self.status == StatusCode::UNAUTHORIZED {
+            response.insert_header(("WWW-Authenticate", "Bearer"));
+        }
+        response.json(ErrorBody {
+            error: ErrorDetail {
+                code: self.code,
+                message: &self.message,
+            },
+        })
+    }
+}
+
+/// Unexpected errors, mostly from the database. Running out of connections
+/// is reported as temporary so that clients know to try again.
+impl From<anyhow::Error> for ApiError {
+    fn from(e: anyhow::Error) -> Self {
+        warn!("{:#}", e);
+
+        match e.downcast_ref::<sqlx::Error>() {
+            Some(sqlx::Error::PoolTimedOut | sqlx::Error::PoolClosed) => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "unavailable",
+                "Database is busy, try again later",
+            ),
+            _ => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "internal",
+                "Internal server error",
+            ),
+        }
+    }
+}
+
+/// Report query strings, bodies and paths that the extractors reject like
+/// any other error, instead of as plain text.
+pub fn configure_extractors(cfg: &mut web::ServiceConfig) {
+    cfg.app_data(
+        web::QueryConfig::default()
+            .error_handler(|e, _| ApiError::bad_request("bad_query", e.to_string()).into()),
+    )
+    .app_data(
+        web::JsonConfig::default()
+            .error_handler(|e, _| ApiError::bad_request("bad_body", e.to_string()).into()),
+    )
+    .app_data(
+        web::PathConfig::default()
+            .error_handler(|e, _| ApiError::bad_request("bad_route", e.to_string()).into()),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{test, App};
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Deserialize)]
+    struct Limit {
+        limit: i64,
+    }
+
+    async fn handler(query: web::Query<Limit>, body: web::Json<Limit>) -> HttpResponse {
+        HttpResponse::Ok().json(query.limit + body.limit)
+    }
+
+    async fn error_code(req: test::TestRequest) -> String {
+        let app = test::init_service(
+            App::new()
+                .configure(configure_extractors)
+                .route("/", web::post().to(handler)),
+        )
+        .await;
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::BAD_REQUEST);
+        let body: serde_json::Value = test::read_body_json(res).await;
+        body["error"]["code"].as_str().unwrap().to_string()
+    }
+
+    #[actix_web::test]
+    async fn reports_extractor_errors_as_json() {
+        let req = test::TestRequest::post()
+            .uri("/?limit=bogus")
+            .set_json(serde_json::json!({ "limit": 1 }));
+        assert_eq!(error_code(req).await, "bad_query");
+
+        let req = test::TestRequest::post()
+            .uri("/?limit=1")
+            .set_payload(r#"{"limit": 1}"#);
+        assert_eq!(error_code(req).await, "bad_body");
+    }
+}
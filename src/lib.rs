@@ -1,5 +1,19 @@
+pub mod adapter;
+pub mod auth;
 pub mod config;
+pub mod dashboard;
 pub mod db;
+pub mod error;
+pub mod live;
+pub mod metrics;
+pub mod preflight;
+pub mod progress;
 pub mod recorder;
+pub mod reload;
+pub mod review;
+#[cfg(test)]
+mod test_util;
+pub mod upgrade;
 pub mod upload;
 pub mod webhook;
+pub mod worker;
@@ -0,0 +1,87 @@
+use std::time::Duration;
+
+use actix_web::web::Bytes;
+use futures::{stream, Stream};
+use serde::Serialize;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use crate::db::UploadStatus;
+use crate::recorder::EventType;
+
+/// Updates not received by a slow client within this many are dropped.
+const CAPACITY: usize = 256;
+
+/// A comment is sent when idle, so that proxies keep the connection open.
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+
+/// An update pushed to clients of `/events`.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub(crate) enum LiveEvent {
+    Status {
+        event_id: String,
+        status: UploadStatus,
+    },
+    Progress {
+        event_id: String,
+        bytes_sent: u64,
+        total: u64,
+        speed: f64,
+        eta: Option<u64>,
+    },
+    Recorder {
+        recorder: &'static str,
+        event_id: String,
+        event_type: EventType,
+        room_id: u64,
+    },
+}
+
+impl LiveEvent {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Status { .. } => "status",
+            Self::Progress { .. } => "progress",
+            Self::Recorder { .. } => "recorder",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Live {
+    sender: broadcast::Sender<LiveEvent>,
+}
+
+impl Default for Live {
+    fn default() -> Self {
+        let (sender, _) = broadcast::channel(CAPACITY);
+        Self { sender }
+    }
+}
+
+impl Live {
+    pub(crate) fn send(&self, event: LiveEvent) {
+        // Nobody is listening most of the time.
+        let _ = self.sender.send(event);
+    }
+
+    /// Updates from now on, encoded as Server-Sent Events.
+    pub(crate) fn subscribe(&self) -> impl Stream<Item = Result<Bytes, actix_web::Error>> {
+        let receiver = self.sender.subscribe();
+
+        stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                let chunk = match tokio::time::timeout(KEEP_ALIVE, receiver.recv()).await {
+                    Err(_) => ": keep-alive\n\n".to_string(),
+                    Ok(Ok(event)) => match serde_json::to_string(&event) {
+                        Ok(data) => format!("event: {}\ndata: {}\n\n", event.name(), data),
+                        Err(_) => continue,
+                    },
+                    Ok(Err(RecvError::Lagged(n))) => format!(": {} updates dropped\n\n", n),
+                    Ok(Err(RecvError::Closed)) => return None,
+                };
+                return Some((Ok(Bytes::from(chunk)), receiver));
+            }
+        })
+    }
+}
@@ -1,64 +1,204 @@
 use actix_web::{web, App, HttpServer};
+use anyhow::{anyhow, bail};
+use clap::{Parser, Subcommand};
 use log::{info, warn};
 use sqlx::sqlite::SqlitePoolOptions;
-use tokio::sync::mpsc;
+
+use arc_swap::ArcSwap;
 
 use biliupmgr::config::ManagerConfig;
+use biliupmgr::dashboard;
 use biliupmgr::db;
-use biliupmgr::recorder::RecorderEvent;
-use biliupmgr::upload;
+use biliupmgr::error;
+use biliupmgr::preflight;
+use biliupmgr::reload;
+use biliupmgr::review;
+use biliupmgr::upgrade;
 use biliupmgr::webhook;
 use biliupmgr::webhook::AppState;
+use biliupmgr::worker;
 
-#[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    env_logger::init();
+/// Upload recordings of live streams to bilibili.
+///
+/// Fields of the config file can be overridden with environment variables,
+/// e.g. `BILIUPMGR__PORT=8080` or `BILIUPMGR__WORKERS__GLOBAL=2`.
+#[derive(Parser, Debug)]
+#[clap(version)]
+struct Args {
+    /// Path of the config file.
+    #[clap(short, long, env = "BILIUPMGR_CONFIG", default_value = "config.yaml")]
+    config: String,
 
-    let config = ManagerConfig::load("config.yaml").expect("Failed to load config");
+    #[clap(long, env = "DATABASE_URL", default_value = "sqlite:biliup.db")]
+    database_url: String,
 
-    let dao = {
-        let pool = SqlitePoolOptions::new()
-            .max_connections(5)
-            .connect("sqlite:biliup.db")
-            .await
-            .expect("Failed to connect to database");
+    /// Address to listen on as `host:port` or `[ipv6]:port`, instead of the one
+    /// in the config.
+    #[clap(short, long, env = "BILIUPMGR_BIND")]
+    bind: Option<String>,
 
-        let dao = db::BiliupDao::new(pool);
-        web::Data::new(dao)
-    };
+    /// Filters for env_logger, e.g. `info` or `biliupmgr=debug`.
+    #[clap(long, env = "RUST_LOG", default_value = "info")]
+    log_level: String,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
 
-    let (tx, mut rx) = mpsc::channel::<RecorderEvent>(16);
-    let tx = web::Data::new(tx);
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Run the server (default).
+    Serve,
+    /// Apply database migrations and exit.
+    Migrate,
+    /// Check the config and the files it refers to, print a report and exit.
+    CheckConfig,
+    /// Rewrite the config file in the latest schema, keeping a backup.
+    UpgradeConfig,
+}
 
-    let state = web::Data::new(AppState::default());
+#[actix_web::main]
+async fn main() -> anyhow::Result<()> {
+    let args = Args::parse();
 
-    let bind_addr = (config.host.clone(), config.port);
-
-    {
-        let dao = dao.clone();
-        let state = state.clone();
-        tokio::spawn(async move {
-            while let Some(event) = rx.recv().await {
-                match upload::upload(&config, &dao, &event, &state).await {
-                    Ok(_) => (),
-                    Err(e) => warn!("{}", e),
-                }
+    env_logger::Builder::new()
+        .parse_filters(&args.log_level)
+        .init();
+
+    match args.command {
+        Some(Command::CheckConfig) => {
+            let report = preflight::check(&ManagerConfig::load(&args.config)?);
+            print!("{}", report);
+            if report.is_fatal() {
+                bail!("{} has errors", args.config);
+            }
+            Ok(())
+        }
+        Some(Command::UpgradeConfig) => {
+            match upgrade::upgrade_file(&args.config)? {
+                Some(backup) => info!(
+                    "Upgraded {}, the original is kept as {}",
+                    args.config,
+                    backup.display()
+                ),
+                None => info!("{} is up to date", args.config),
             }
-        });
+            Ok(())
+        }
+        Some(Command::Migrate) => {
+            let pool = connect(&args).await?;
+            sqlx::migrate!().run(&pool).await?;
+            info!("Database is up to date");
+            Ok(())
+        }
+        Some(Command::Serve) | None => serve(&args).await,
     }
+}
+
+async fn connect(args: &Args) -> anyhow::Result<sqlx::SqlitePool> {
+    let pool = SqlitePoolOptions::new()
+        .max_connections(5)
+        .connect(&args.database_url)
+        .await?;
+    Ok(pool)
+}
+
+async fn serve(args: &Args) -> anyhow::Result<()> {
+    let config = ManagerConfig::load(&args.config)?;
 
-    info!("Starting server at http://{}:{}", bind_addr.0, bind_addr.1);
+    // Refuse to start rather than fail every upload hours later.
+    let report = preflight::check(&config);
+    report.log();
+    if report.is_fatal() {
+        bail!("{} has errors, see `biliupmgr check-config`", args.config);
+    }
+    if config.auth.tokens.is_empty() {
+        warn!("No API token is configured, anyone who can reach the server can control it");
+    } else if config.auth.webhook_secret.is_none() && config.auth.webhook_hmac.is_none() {
+        warn!("Neither webhook_secret nor webhook_hmac is configured, anyone who can reach the server can queue uploads");
+    }
+
+    let bind_addr = match &args.bind {
+        Some(bind) => parse_bind(bind)?,
+        None => (config.host.clone(), config.port),
+    };
+
+    let dao = web::Data::new(db::BiliupDao::new(connect(args).await?));
+    let state = web::Data::new(AppState::default());
+    state.reload_paused(&dao).await?;
+    let config = web::Data::new(ArcSwap::from_pointee(config));
+
+    tokio::spawn(worker::run(
+        config.clone().into_inner(),
+        dao.clone(),
+        state.clone(),
+    ));
+    tokio::spawn(review::watch(
+        config.clone().into_inner(),
+        dao.clone(),
+        state.clone(),
+    ));
+    tokio::spawn(reload::watch(
+        args.config.clone(),
+        config.clone().into_inner(),
+    ));
+
+    let display_host = match &bind_addr.0 {
+        host if host.contains(':') => format!("[{}]", host),
+        host => host.clone(),
+    };
+    info!("Starting server at http://{}:{}", display_host, bind_addr.1);
     HttpServer::new(move || {
         App::new()
-            .app_data(tx.clone())
             .app_data(dao.clone())
             .app_data(state.clone())
-            .service(webhook::status)
-            .service(webhook::status_ok)
-            .service(webhook::recorder)
-            .service(webhook::retry)
+            .app_data(config.clone())
+            .configure(error::configure_extractors)
+            .service(web::scope("/api/v1").configure(webhook::configure))
+            .configure(webhook::configure)
+            .service(dashboard::index)
     })
     .bind(bind_addr)?
     .run()
-    .await
+    .await?;
+
+    Ok(())
+}
+
+/// Split `host:port`, where IPv6 hosts are written in brackets, e.g.
+/// `[::1]:23380`.
+fn parse_bind(bind: &str) -> anyhow::Result<(String, u16)> {
+    let (host, port) = bind
+        .rsplit_once(':')
+        .ok_or_else(|| anyhow!("Invalid address {}, expected host:port", bind))?;
+    let host = match host.strip_prefix('[') {
+        Some(host) => host
+            .strip_suffix(']')
+            .ok_or_else(|| anyhow!("Invalid address {}, expected [host]:port", bind))?,
+        None => host,
+    };
+    let port = port
+        .parse()
+        .map_err(|_| anyhow!("Invalid port in {}", bind))?;
+    Ok((host.to_string(), port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_bind_addresses() {
+        assert_eq!(
+            parse_bind("0.0.0.0:23380").unwrap(),
+            ("0.0.0.0".to_string(), 23380)
+        );
+        assert_eq!(
+            parse_bind("localhost:8080").unwrap(),
+            ("localhost".to_string(), 8080)
+        );
+        assert_eq!(parse_bind("[::1]:8080").unwrap(), ("::1".to_string(), 8080));
+        assert!(parse_bind("[::1:8080").is_err());
+        assert!(parse_bind("localhost").is_err());
+    }
 }
@@ -0,0 +1,132 @@
+use std::fmt;
+
+use prometheus::{
+    exponential_buckets, Encoder, GaugeVec, Histogram, HistogramOpts, HistogramVec, IntCounterVec,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+
+use crate::config::cookie_expiry;
+
+/// Prometheus metrics, served in text format at `/metrics`.
+pub(crate) struct Metrics {
+    registry: Registry,
+
+    /// Recorder events by recorder and event type.
+    pub(crate) events: IntCounterVec,
+    /// Uploads by room, upload line and result. Every line that fails to
+    /// upload a file is counted as failed, and a successful job under the line
+    /// the file went through. Jobs that fail or are cancelled are counted
+    /// under an empty line, as are those reusing an uploaded file.
+    pub(crate) uploads: IntCounterVec,
+    pub(crate) bytes_uploaded: IntCounterVec,
+    pub(crate) upload_duration: HistogramVec,
+    /// Seconds between an upload becoming due and a worker starting it.
+    pub(crate) time_in_queue: Histogram,
+    /// Uploads by status, refreshed on every scrape.
+    pub(crate) uploads_by_status: IntGaugeVec,
+    /// Failed calls to the submission API by call.
+    pub(crate) api_errors: IntCounterVec,
+    /// Unix timestamp at which the login cookie of an account expires.
+    pub(crate) cookie_expiry: GaugeVec,
+}
+
+impl Metrics {
+    pub(crate) fn new() -> prometheus::Result<Self> {
+        let registry = Registry::new_custom(Some("biliupmgr".to_string()), None)?;
+
+        let events = IntCounterVec::new(
+            Opts::new("events_received_total", "Recorder events received"),
+            &["recorder", "event_type"],
+        )?;
+        let uploads = IntCounterVec::new(
+            Opts::new("uploads_total", "Uploads finished, per line and per job"),
+            &["room_id", "line", "result"],
+        )?;
+        let bytes_uploaded = IntCounterVec::new(
+            Opts::new("uploaded_bytes_total", "Bytes of video files uploaded"),
+            &["room_id"],
+        )?;
+        // From 10 seconds to about 3 hours.
+        let upload_duration = HistogramVec::new(
+            HistogramOpts::new("upload_duration_seconds", "Duration of upload jobs")
+                .buckets(exponential_buckets(10.0, 2.0, 11)?),
+            &["room_id"],
+        )?;
+        let time_in_queue = Histogram::with_opts(
+            HistogramOpts::new(
+                "queue_wait_seconds",
+                "Time spent in the queue before upload",
+            )
+            .buckets(exponential_buckets(1.0, 4.0, 9)?),
+        )?;
+        let uploads_by_status = IntGaugeVec::new(
+            Opts::new("uploads", "Uploads in the database by status"),
+            &["status"],
+        )?;
+        let api_errors = IntCounterVec::new(
+            Opts::new("api_errors_total", "Failed calls to the submission API"),
+            &["api"],
+        )?;
+        let cookie_expiry = GaugeVec::new(
+            Opts::new(
+                "cookie_expiry_timestamp_seconds",
+                "Expiry of the login cookie",
+            ),
+            &["account"],
+        )?;
+
+        registry.register(Box::new(events.clone()))?;
+        registry.register(Box::new(uploads.clone()))?;
+        registry.register(Box::new(bytes_uploaded.clone()))?;
+        registry.register(Box::new(upload_duration.clone()))?;
+        registry.register(Box::new(time_in_queue.clone()))?;
+        registry.register(Box::new(uploads_by_status.clone()))?;
+        registry.register(Box::new(api_errors.clone()))?;
+        registry.register(Box::new(cookie_expiry.clone()))?;
+
+        Ok(Self {
+            registry,
+            events,
+            uploads,
+            bytes_uploaded,
+            upload_duration,
+            time_in_queue,
+            uploads_by_status,
+            api_errors,
+            cookie_expiry,
+        })
+    }
+
+    pub(crate) fn render(&self) -> prometheus::Result<String> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        Ok(String::from_utf8_lossy(&buffer).into_owned())
+    }
+
+    /// Count a failed call to `api` and pass the error on.
+    pub(crate) fn api_error<E>(&self, api: &str, error: E) -> E {
+        self.api_errors.with_label_values(&[api]).inc();
+        error
+    }
+
+    /// Read the expiry of `SESSDATA` from a cookie file written by biliup.
+    pub(crate) fn update_cookie_expiry(&self, account: &str) {
+        if let Ok(expires) = cookie_expiry(account) {
+            self.cookie_expiry
+                .with_label_values(&[account])
+                .set(expires as f64);
+        }
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new().expect("Invalid metric definitions")
+    }
+}
+
+impl fmt::Debug for Metrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Metrics").finish_non_exhaustive()
+    }
+}
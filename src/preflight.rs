@@ -0,0 +1,187 @@
+use std::fmt;
+use std::path::Path;
+
+use log::{error, warn};
+
+use crate::config::{cookie_expiry, ManagerConfig, RoomConfig};
+use crate::recorder::RecorderEventData;
+
+/// Logins expiring within this many seconds are reported.
+const COOKIE_WARNING: i64 = 7 * 24 * 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+#[derive(Debug)]
+pub struct Issue {
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// Problems found in a config, by room.
+#[derive(Debug, Default)]
+pub struct Report {
+    pub global: Vec<Issue>,
+    pub rooms: Vec<(u64, Vec<Issue>)>,
+}
+
+impl Report {
+    pub fn log(&self) {
+        let issues = self
+            .global
+            .iter()
+            .map(|issue| (String::new(), issue))
+            .chain(self.rooms.iter().flat_map(|(room_id, issues)| {
+                issues
+                    .iter()
+                    .map(move |issue| (format!("room {}: ", room_id), issue))
+            }));
+
+        for (prefix, issue) in issues {
+            match issue.severity {
+                Severity::Warning => warn!("{}{}", prefix, issue.message),
+                Severity::Error => error!("{}{}", prefix, issue.message),
+            }
+        }
+    }
+
+    /// Whether uploads are bound to fail with this config.
+    pub fn is_fatal(&self) -> bool {
+        self.global
+            .iter()
+            .chain(self.rooms.iter().flat_map(|(_, issues)| issues))
+            .any(|issue| issue.severity == Severity::Error)
+    }
+}
+
+/// Check a config before it is used: [`ManagerConfig::validate`], then what
+/// the config refers to, i.e. directories, cookie files, covers and templates.
+pub fn check(config: &ManagerConfig) -> Report {
+    let mut report = Report::default();
+
+    if let Err(e) = config.validate() {
+        report.global.push(error(format!("{:#}", e)));
+    }
+    if let Err(e) = std::fs::read_dir(&config.rec_dir) {
+        report.global.push(error(format!(
+            "rec_dir {} is not readable: {}",
+            config.rec_dir, e
+        )));
+    }
+    for dir in &config.extra_rec_dirs {
+        if !Path::new(dir).is_dir() {
+            report.global.push(warning(format!(
+                "extra_rec_dirs: {} is not a directory",
+                dir
+            )));
+        }
+    }
+
+    let mut room_ids: Vec<_> = config.rooms.keys().copied().collect();
+    room_ids.sort_unstable();
+    for room_id in room_ids {
+        report
+            .rooms
+            .push((room_id, check_room(&config.rooms[&room_id])));
+    }
+
+    report
+}
+
+fn check_room(room: &RoomConfig) -> Vec<Issue> {
+    let mut issues = Vec::new();
+
+    match cookie_expiry(&room.user_cookie) {
+        Ok(expires) => {
+            let left = expires - chrono::Utc::now().timestamp();
+            if left <= 0 {
+                issues.push(error(format!(
+                    "user_cookie {}: login has expired",
+                    room.user_cookie
+                )));
+            } else if left < COOKIE_WARNING {
+                issues.push(warning(format!(
+                    "user_cookie {}: login expires in {} hours",
+                    room.user_cookie,
+                    left / 3600
+                )));
+            }
+        }
+        Err(e) => issues.push(error(format!("user_cookie {}: {:#}", room.user_cookie, e))),
+    }
+
+    if !room.cover.starts_with("http://") && !room.cover.starts_with("https://") {
+        if let Err(e) = std::fs::metadata(&room.cover) {
+            issues.push(error(format!("cover {}: {}", room.cover, e)));
+        }
+    }
+
+    if room.tid == 0 {
+        issues.push(error("tid must be set to a category".to_string()));
+    }
+
+    if room.tags.iter().all(|tag| tag.trim().is_empty()) {
+        issues.push(error("tags must not be empty".to_string()));
+    }
+
+    for (name, template) in [
+        ("studio_title", &room.studio_title),
+        ("part_title", &room.part_title),
+    ] {
+        let unknown = RecorderEventData::unknown_placeholders(template);
+        if !unknown.is_empty() {
+            issues.push(error(format!(
+                "{}: unknown placeholders {}",
+                name,
+                unknown.join(", ")
+            )));
+        }
+    }
+
+    issues
+}
+
+fn error(message: String) -> Issue {
+    Issue {
+        severity: Severity::Error,
+        message,
+    }
+}
+
+fn warning(message: String) -> Issue {
+    Issue {
+        severity: Severity::Warning,
+        message,
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Warning => write!(f, "warning: {}", self.message),
+            Severity::Error => write!(f, "error: {}", self.message),
+        }
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for issue in &self.global {
+            writeln!(f, "{}", issue)?;
+        }
+        for (room_id, issues) in &self.rooms {
+            if issues.is_empty() {
+                writeln!(f, "room {}: ok", room_id)?;
+                continue;
+            }
+            writeln!(f, "room {}:", room_id)?;
+            for issue in issues {
+                writeln!(f, "  {}", issue)?;
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,78 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::db::UploadStatus;
+
+/// Throughput is averaged over the chunks finished within this window.
+const SPEED_WINDOW: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Progress {
+    pub phase: UploadStatus,
+    pub bytes_sent: u64,
+    pub total: u64,
+    pub chunks: u64,
+    /// Bytes per second.
+    pub speed: f64,
+    /// Seconds left at the current speed.
+    pub eta: Option<u64>,
+
+    #[serde(skip)]
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl Progress {
+    pub fn new(total: u64) -> Self {
+        Self {
+            phase: UploadStatus::Queued,
+            bytes_sent: 0,
+            total,
+            chunks: 0,
+            speed: 0.0,
+            eta: None,
+            samples: VecDeque::new(),
+        }
+    }
+
+    pub fn add_chunk(&mut self, len: usize) {
+        let now = Instant::now();
+        let len = len as u64;
+
+        self.bytes_sent += len;
+        self.chunks += 1;
+
+        self.samples.push_back((now, len));
+        while let Some((at, _)) = self.samples.front() {
+            if now.duration_since(*at) <= SPEED_WINDOW {
+                break;
+            }
+            self.samples.pop_front();
+        }
+
+        // Until the window fills up, average over the time actually covered.
+        let elapsed = match self.samples.front() {
+            Some((first, _)) if self.samples.len() > 1 => now.duration_since(*first),
+            _ => return,
+        };
+        let bytes: u64 = self.samples.iter().skip(1).map(|(_, len)| len).sum();
+        self.speed = bytes as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+
+        self.eta = if self.speed > 0.0 {
+            let left = self.total.saturating_sub(self.bytes_sent);
+            Some((left as f64 / self.speed).ceil() as u64)
+        } else {
+            None
+        };
+    }
+
+    pub fn set_phase(&mut self, phase: UploadStatus) {
+        self.phase = phase;
+        if phase != UploadStatus::Uploading {
+            self.speed = 0.0;
+            self.eta = None;
+            self.samples.clear();
+        }
+    }
+}
@@ -1,16 +1,174 @@
+use std::fmt;
+use std::path::{Component, Path};
+
+use anyhow::bail;
 use chrono::{DateTime, FixedOffset, Timelike};
 use serde::{Deserialize, Serialize};
 
+/// Placeholders of title templates: the stream title, the streamer name, the
+/// date of the stream, the time of the recording and a literal `%`.
+pub const PLACEHOLDERS: &[&str] = &["%T", "%N", "%d", "%t", "%%"];
+
+/// Event types of BililiveRecorder webhook v2.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, sqlx::Type)]
+pub enum EventType {
+    SessionStarted,
+    SessionEnded,
+    StreamStarted,
+    StreamEnded,
+    FileOpening,
+    FileClosed,
+}
+
+impl EventType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::SessionStarted => "SessionStarted",
+            Self::SessionEnded => "SessionEnded",
+            Self::StreamStarted => "StreamStarted",
+            Self::StreamEnded => "StreamEnded",
+            Self::FileOpening => "FileOpening",
+            Self::FileClosed => "FileClosed",
+        }
+    }
+}
+
+impl fmt::Display for EventType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// A recorded file ready to be uploaded, i.e. a `FileClosed` event.
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RecorderEvent {
     #[serde(rename = "EventId")]
     pub event_id: String,
     #[serde(rename = "EventType")]
-    pub event_type: String,
+    pub event_type: EventType,
     #[serde(rename = "EventData")]
     pub event_data: RecorderEventData,
 }
 
+/// Any event posted by the recorder.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecorderWebhook {
+    #[serde(rename = "EventId")]
+    pub event_id: String,
+    #[serde(
+        rename = "EventTimestamp",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub event_timestamp: Option<String>,
+    #[serde(flatten)]
+    pub payload: RecorderPayload,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "EventType", content = "EventData")]
+pub enum RecorderPayload {
+    SessionStarted(SessionData),
+    SessionEnded(SessionData),
+    StreamStarted(RoomInfo),
+    StreamEnded(RoomInfo),
+    FileOpening(FileOpeningData),
+    FileClosed(RecorderEventData),
+}
+
+impl RecorderPayload {
+    pub fn event_type(&self) -> EventType {
+        match self {
+            Self::SessionStarted(_) => EventType::SessionStarted,
+            Self::SessionEnded(_) => EventType::SessionEnded,
+            Self::StreamStarted(_) => EventType::StreamStarted,
+            Self::StreamEnded(_) => EventType::StreamEnded,
+            Self::FileOpening(_) => EventType::FileOpening,
+            Self::FileClosed(_) => EventType::FileClosed,
+        }
+    }
+
+    pub fn room_id(&self) -> u64 {
+        match self {
+            Self::SessionStarted(data) | Self::SessionEnded(data) => data.room.room_id,
+            Self::StreamStarted(room) | Self::StreamEnded(room) => room.room_id,
+            Self::FileOpening(data) => data.room.room_id,
+            Self::FileClosed(data) => data.room_id,
+        }
+    }
+
+    pub fn session_id(&self) -> Option<&str> {
+        match self {
+            Self::SessionStarted(data) | Self::SessionEnded(data) => Some(&data.session_id),
+            Self::StreamStarted(_) | Self::StreamEnded(_) => None,
+            Self::FileOpening(data) => Some(&data.session_id),
+            Self::FileClosed(data) => data.session_id.as_deref(),
+        }
+    }
+}
+
+impl RecorderWebhook {
+    /// When the recorder sent the event, if it tells.
+    pub fn timestamp(&self) -> Option<DateTime<FixedOffset>> {
+        DateTime::parse_from_rfc3339(self.event_timestamp.as_deref()?).ok()
+    }
+
+    /// The file to upload, if this is a `FileClosed` event.
+    pub fn into_file_event(self) -> Option<RecorderEvent> {
+        match self.payload {
+            RecorderPayload::FileClosed(event_data) => Some(RecorderEvent {
+                event_id: self.event_id,
+                event_type: EventType::FileClosed,
+                event_data,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Room information attached to every event.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RoomInfo {
+    #[serde(rename = "RoomId")]
+    pub room_id: u64,
+    #[serde(rename = "ShortId", default)]
+    pub short_id: u64,
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "Title")]
+    pub title: String,
+    #[serde(rename = "AreaNameParent", default)]
+    pub area_name_parent: String,
+    #[serde(rename = "AreaNameChild", default)]
+    pub area_name_child: String,
+    #[serde(rename = "Recording", default)]
+    pub recording: bool,
+    #[serde(rename = "Streaming", default)]
+    pub streaming: bool,
+    #[serde(rename = "DanmakuConnected", default)]
+    pub danmaku_connected: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SessionData {
+    #[serde(flatten)]
+    pub room: RoomInfo,
+    #[serde(rename = "SessionId")]
+    pub session_id: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FileOpeningData {
+    #[serde(flatten)]
+    pub room: RoomInfo,
+    #[serde(rename = "SessionId")]
+    pub session_id: String,
+    #[serde(rename = "RelativePath")]
+    pub relative_path: String,
+    #[serde(rename = "FileOpenTime")]
+    pub file_open_time: String,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RecorderEventData {
     #[serde(rename = "RoomId")]
@@ -27,9 +185,36 @@ pub struct RecorderEventData {
     pub file_size: u64,
     #[serde(rename = "Duration")]
     pub duration: f64,
+    /// Set by adapters when the recorder does not tell the duration, which is
+    /// 0 then. Not stored.
+    #[serde(skip)]
+    pub duration_unknown: bool,
+    #[serde(rename = "SessionId", default, skip_serializing_if = "Option::is_none")]
+    pub session_id: Option<String>,
 }
 
 impl RecorderEventData {
+    /// `RelativePath` comes from the network, so it must not be absolute nor
+    /// climb out of the recording directory.
+    pub fn check_relative_path(&self) -> anyhow::Result<()> {
+        let path = Path::new(&self.relative_path);
+        let confined = path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+
+        if self.relative_path.is_empty() || !confined {
+            bail!("Invalid relative path {:?}", self.relative_path);
+        }
+        Ok(())
+    }
+
+    /// Start and end of the recorded file.
+    pub fn time_range(&self) -> Option<(DateTime<FixedOffset>, DateTime<FixedOffset>)> {
+        let start = DateTime::parse_from_rfc3339(&self.file_open_time).ok()?;
+        let end = start + chrono::Duration::milliseconds((self.duration * 1000.0) as i64);
+        Some((start, end))
+    }
+
     pub fn format(&self, format: &str) -> String {
         Self::format_parse(format)
             .iter()
@@ -39,12 +224,21 @@ impl RecorderEventData {
                     "%N" => acc.push_str(&self.name),
                     "%d" => acc.push_str(&self.date_string()),
                     "%t" => acc.push_str(&self.time_string()),
+                    "%%" => acc.push('%'),
                     _ => acc.push_str(s),
                 }
                 acc
             })
     }
 
+    /// Placeholders in `format` that [`Self::format`] would leave as is.
+    pub fn unknown_placeholders(format: &str) -> Vec<String> {
+        Self::format_parse(format)
+            .into_iter()
+            .filter(|s| s.starts_with('%') && s != "%" && !PLACEHOLDERS.contains(&s.as_str()))
+            .collect()
+    }
+
     fn date_string(&self) -> String {
         let mut date = DateTime::parse_from_rfc3339(&self.file_open_time).unwrap();
         if date.hour() < 4 {
@@ -66,20 +260,65 @@ impl RecorderEventData {
         formatted_time.to_string()
     }
 
+    /// Split `format` into placeholders, i.e. `%` and the character after
+    /// it, and the text between them. A `%` at the end is kept as text.
     fn format_parse(format: &str) -> Vec<String> {
         let mut result: Vec<String> = Vec::new();
-        let mut last = 0;
-        for (index, _) in format.match_indices('%') {
-            if last != index {
-                result.push(format[last..index].to_string());
-            };
-            if index == format.len() - 1 {
-                result.push("%".to_string());
-                break;
+        let mut text = String::new();
+        let mut chars = format.chars();
+        while let Some(c) = chars.next() {
+            match (c, chars.clone().next()) {
+                ('%', Some(next)) => {
+                    chars.next();
+                    if !text.is_empty() {
+                        result.push(std::mem::take(&mut text));
+                    }
+                    result.push(format!("%{}", next));
+                }
+                _ => text.push(c),
             }
-            result.push(format[index..index + 2].to_string());
-            last = index + 2;
+        }
+        if !text.is_empty() {
+            result.push(text);
         }
         result
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::event_data as data;
+
+    #[test]
+    fn formats_titles() {
+        let data = data("a.flv");
+        assert_eq!(data.format("%N: %T"), "name: title");
+        assert_eq!(data.format("%d %t"), "2021.06.01 20210601-200000");
+        assert_eq!(data.format("plain"), "plain");
+        assert_eq!(data.format("100%% %T 100%"), "100% title 100%");
+        assert_eq!(data.format("%é直播%T"), "%é直播title");
+    }
+
+    #[test]
+    fn finds_unknown_placeholders() {
+        assert_eq!(
+            RecorderEventData::unknown_placeholders("%T %x %% %é"),
+            ["%x", "%é"]
+        );
+        assert!(RecorderEventData::unknown_placeholders("%").is_empty());
+    }
+
+    #[test]
+    fn accepts_paths_below_rec_dir() {
+        assert!(data("1/a.flv").check_relative_path().is_ok());
+        assert!(data("./1/a.flv").check_relative_path().is_ok());
+    }
+
+    #[test]
+    fn rejects_escaping_paths() {
+        for path in ["", "/etc/passwd", "../a.flv", "1/../../a.flv"] {
+            assert!(data(path).check_relative_path().is_err(), "{:?}", path);
+        }
+    }
+}
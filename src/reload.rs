@@ -0,0 +1,87 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use log::{info, warn};
+
+use crate::config::{ManagerConfig, SharedConfig};
+use crate::preflight;
+
+/// How often the config file is checked for changes.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Reload the config on SIGHUP or when the file changes.
+///
+/// A new config only replaces the current one if it is valid. Jobs already
+/// running keep the snapshot they started with.
+pub async fn watch(path: String, config: Arc<SharedConfig>) {
+    let mut modified = modified_at(&path);
+    let mut poll = tokio::time::interval(POLL_INTERVAL);
+
+    #[cfg(unix)]
+    let mut hangup = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(signal) => Some(signal),
+        Err(e) => {
+            warn!("Failed to listen to SIGHUP: {}", e);
+            None
+        }
+    };
+
+    loop {
+        #[cfg(unix)]
+        let hangup_received = async {
+            match hangup.as_mut() {
+                Some(signal) => signal.recv().await,
+                None => std::future::pending().await,
+            }
+        };
+        #[cfg(not(unix))]
+        let hangup_received = std::future::pending::<Option<()>>();
+
+        tokio::select! {
+            _ = hangup_received => {
+                info!("Received SIGHUP, reloading {}", path);
+            }
+            _ = poll.tick() => {
+                let current = modified_at(&path);
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+                info!("{} changed, reloading", path);
+            }
+        }
+
+        reload(&path, &config);
+    }
+}
+
+pub fn reload(path: &str, config: &SharedConfig) {
+    let new = match ManagerConfig::load(path) {
+        Ok(new) => new,
+        Err(e) => {
+            warn!("Keeping the current config, {} is invalid: {:#}", path, e);
+            return;
+        }
+    };
+
+    let report = preflight::check(&new);
+    report.log();
+    if report.is_fatal() {
+        warn!("Keeping the current config, {} has errors", path);
+        return;
+    }
+
+    let old = config.load();
+    if (&old.host, old.port) != (&new.host, new.port) {
+        warn!("Changing the address to listen on requires a restart");
+    }
+
+    config.store(Arc::new(new));
+    info!("Reloaded {}", path);
+}
+
+fn modified_at(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .ok()
+}
@@ -0,0 +1,135 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::web;
+use anyhow::anyhow;
+use biliup::{
+    client::{Client, LoginInfo},
+    video::{BiliBili, Vid},
+};
+use log::{debug, info, warn};
+
+use crate::config::{ManagerConfig, SharedConfig};
+use crate::db::{BiliupDao, PendingReview, UploadStatus};
+use crate::webhook::AppState;
+
+/// Follow the review of submitted archives, every `review_interval` seconds,
+/// until they are published or rejected.
+pub async fn watch(
+    config: Arc<SharedConfig>,
+    dao: web::Data<BiliupDao>,
+    state: web::Data<AppState>,
+) {
+    loop {
+        let interval = config.load().review_interval;
+        tokio::time::sleep(Duration::from_secs(interval)).await;
+
+        if let Err(e) = check(&config.load_full(), &dao, &state).await {
+            warn!("Failed to check reviews: {:#}", e);
+        }
+    }
+}
+
+async fn check(config: &ManagerConfig, dao: &BiliupDao, state: &AppState) -> anyhow::Result<()> {
+    let mut archives: BTreeMap<u64, Vec<PendingReview>> = BTreeMap::new();
+    for upload in dao.pending_reviews().await? {
+        archives.entry(upload.aid).or_default().push(upload);
+    }
+
+    // Logins by cookie file, as rooms often share an account.
+    let mut logins: HashMap<String, (Client, LoginInfo)> = HashMap::new();
+    for (aid, uploads) in archives {
+        let room = match config.rooms.get(&uploads[0].room_id) {
+            Some(room) => room,
+            None => {
+                debug!(
+                    "Room of av{} is not configured, not checking its review",
+                    aid
+                );
+                continue;
+            }
+        };
+
+        if !logins.contains_key(&room.user_cookie) {
+            match login(&room.user_cookie, state).await {
+                Ok(login) => {
+                    logins.insert(room.user_cookie.clone(), login);
+                }
+                Err(e) => {
+                    warn!("Failed to log in with {}: {:#}", room.user_cookie, e);
+                    continue;
+                }
+            }
+        }
+        let (client, login_info) = &logins[&room.user_cookie];
+
+        let status = match archive_state(client, login_info, aid).await {
+            Ok(archive_state) => review_status(archive_state),
+            Err(e) => {
+                let e = state.metrics.api_error("video_data", e);
+                warn!("Failed to check the review of av{}: {:#}", aid, e);
+                continue;
+            }
+        };
+
+        for upload in uploads {
+            if upload.status == status {
+                continue;
+            }
+            info!("av{} of {} is {}", aid, upload.event_id, status);
+            dao.set_status(&upload.event_id, status).await?;
+            state.publish_status(&upload.event_id, status);
+        }
+    }
+
+    Ok(())
+}
+
+async fn login(user_cookie: &str, state: &AppState) -> anyhow::Result<(Client, LoginInfo)> {
+    let client = Client::default();
+    let cookies_file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .open(user_cookie)?;
+    let login_info = client
+        .login_by_cookies(cookies_file)
+        .await
+        .map_err(|e| state.metrics.api_error("login", e))?;
+    Ok((client, login_info))
+}
+
+async fn archive_state(client: &Client, login_info: &LoginInfo, aid: u64) -> anyhow::Result<i64> {
+    let data = BiliBili::new(login_info, client)
+        .video_data(Vid::Aid(aid))
+        .await?;
+    data["archive"]["state"]
+        .as_i64()
+        .ok_or_else(|| anyhow!("Unexpected archive of av{}: {}", aid, data))
+}
+
+/// Map the `state` of an archive on bilibili to the status of its uploads.
+/// Open archives are at 0 or above. Negative states are still in review,
+/// except for those that need the uploader to act.
+fn review_status(archive_state: i64) -> UploadStatus {
+    match archive_state {
+        0.. => UploadStatus::Published,
+        // Returned for changes, locked, transcoding failed.
+        -2 | -4 | -16 => UploadStatus::Rejected,
+        _ => UploadStatus::UnderReview,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_archive_states() {
+        assert_eq!(review_status(0), UploadStatus::Published);
+        assert_eq!(review_status(-1), UploadStatus::UnderReview);
+        assert_eq!(review_status(-30), UploadStatus::UnderReview);
+        assert_eq!(review_status(-2), UploadStatus::Rejected);
+        assert_eq!(review_status(-16), UploadStatus::Rejected);
+    }
+}
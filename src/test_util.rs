@@ -0,0 +1,40 @@
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config::ManagerConfig;
+use crate::recorder::RecorderEventData;
+
+/// The settings every config needs, followed by `rest`.
+pub(crate) fn config_yaml(rest: &str) -> String {
+    format!(
+        "version: 2\nhost: 127.0.0.1\nport: 23380\nrec_dir: /tmp\n{}",
+        rest
+    )
+}
+
+/// A config without rooms, followed by `rest`, e.g. an `auth` section.
+pub(crate) fn config(rest: &str) -> ManagerConfig {
+    serde_yaml::from_str(&config_yaml(&format!("rooms: {{}}\n{}", rest))).unwrap()
+}
+
+pub(crate) fn event_data(relative_path: &str) -> RecorderEventData {
+    RecorderEventData {
+        room_id: 1,
+        name: "name".to_string(),
+        title: "title".to_string(),
+        relative_path: relative_path.to_string(),
+        file_open_time: "2021-06-01T20:00:00+08:00".to_string(),
+        file_size: 0,
+        duration: 0.0,
+        duration_unknown: false,
+        session_id: None,
+    }
+}
+
+/// An empty directory of its own for each test.
+pub(crate) fn temp_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("biliupmgr-{}-{}", std::process::id(), name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
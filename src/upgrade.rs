@@ -0,0 +1,183 @@
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Result};
+use serde_yaml::{Mapping, Value};
+
+/// Version of the config schema read by this build.
+///
+/// 1. The initial schema.
+/// 2. `tags` of rooms is a list instead of comma-separated, and `line` a
+///    list of lines to fall back on.
+pub const CONFIG_VERSION: u32 = 2;
+
+/// Bring a parsed config file up to [`CONFIG_VERSION`], one version at a
+/// time. Returns the version it was at.
+pub fn upgrade(config: &mut Value) -> Result<u32> {
+    let mapping = config
+        .as_mapping_mut()
+        .ok_or_else(|| anyhow!("The config must be a mapping"))?;
+
+    let version = match mapping.get(&key("version")) {
+        // Files written before versions were checked.
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| anyhow!("Invalid config version {:?}", version))?,
+    };
+    if version == 0 || version > CONFIG_VERSION {
+        bail!(
+            "Unsupported config version {}, this build reads versions up to {}",
+            version,
+            CONFIG_VERSION
+        );
+    }
+
+    for from in version..CONFIG_VERSION {
+        match from {
+            1 => v1_to_v2(mapping),
+            _ => unreachable!(),
+        }
+        mapping.insert(key("version"), Value::Number((from + 1).into()));
+    }
+
+    Ok(version)
+}
+
+/// Upgrade a config file in place, keeping the original next to it. Returns
+/// the path of the backup, or `None` if the file is up to date.
+///
+/// The file is written anew, so comments in it are lost.
+pub fn upgrade_file(path: &str) -> Result<Option<PathBuf>> {
+    let content = std::fs::read_to_string(path)?;
+    let mut config: Value = serde_yaml::from_str(&content)?;
+
+    let version = upgrade(&mut config)?;
+    if version == CONFIG_VERSION {
+        return Ok(None);
+    }
+
+    let backup = PathBuf::from(format!("{}.v{}.bak", path, version));
+    if backup.exists() {
+        bail!("Backup {} already exists", backup.display());
+    }
+    std::fs::copy(path, &backup)?;
+
+    // Replace the file at once, so that a reload never sees half of it.
+    let tmp = Path::new(path).with_extension("tmp");
+    std::fs::write(&tmp, serde_yaml::to_string(&config)?)?;
+    std::fs::rename(&tmp, path)?;
+
+    Ok(Some(backup))
+}
+
+fn v1_to_v2(config: &mut Mapping) {
+    if let Some(line) = config.get_mut(&key("line")) {
+        if line.is_string() {
+            *line = Value::Sequence(vec![line.clone()]);
+        }
+    }
+
+    let rooms = match config
+        .get_mut(&key("rooms"))
+        .and_then(Value::as_mapping_mut)
+    {
+        Some(rooms) => rooms,
+        None => return,
+    };
+    let room_ids: Vec<Value> = rooms.iter().map(|(room_id, _)| room_id.clone()).collect();
+    for room_id in room_ids {
+        let tags = match rooms
+            .get_mut(&room_id)
+            .and_then(|room| room.get_mut("tags"))
+        {
+            Some(tags) => tags,
+            None => continue,
+        };
+        if let Some(list) = tags.as_str() {
+            let list = list
+                .split(',')
+                .map(str::trim)
+                .filter(|tag| !tag.is_empty())
+                .map(|tag| Value::String(tag.to_string()))
+                .collect();
+            *tags = Value::Sequence(list);
+        }
+    }
+}
+
+fn key(name: &str) -> Value {
+    Value::String(name.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::temp_dir;
+
+    const V1: &str = "host: 127.0.0.1
+line: kodo
+rooms:
+  1:
+    tags: 'a, b,,c '
+";
+
+    fn upgraded(yaml: &str) -> Result<(u32, Value)> {
+        let mut config: Value = serde_yaml::from_str(yaml).unwrap();
+        let version = upgrade(&mut config)?;
+        Ok((version, config))
+    }
+
+    #[test]
+    fn upgrades_unversioned_configs() {
+        let (version, config) = upgraded(V1).unwrap();
+        assert_eq!(version, 1);
+        assert_eq!(config["version"], Value::from(CONFIG_VERSION));
+        assert_eq!(
+            config["line"],
+            serde_yaml::from_str::<Value>("[kodo]").unwrap()
+        );
+        assert_eq!(
+            config["rooms"][1]["tags"],
+            serde_yaml::from_str::<Value>("[a, b, c]").unwrap()
+        );
+    }
+
+    #[test]
+    fn keeps_current_configs() {
+        let yaml = "version: 2\nline: [kodo, ws]\nrooms: {1: {tags: [a]}}\n";
+        let (version, config) = upgraded(yaml).unwrap();
+        assert_eq!(version, CONFIG_VERSION);
+        assert_eq!(config, serde_yaml::from_str::<Value>(yaml).unwrap());
+    }
+
+    #[test]
+    fn rejects_unsupported_versions() {
+        assert!(upgraded("version: 3\n").is_err());
+        assert!(upgraded("version: 0\n").is_err());
+        assert!(upgraded("version: two\n").is_err());
+        assert!(upgraded("- version\n").is_err());
+    }
+
+    #[test]
+    fn upgrades_files_once() {
+        let dir = temp_dir("upgrade");
+        let path = dir.join("config.yaml");
+        let path = path.to_str().unwrap();
+        fs::write(path, V1).unwrap();
+
+        let backup = upgrade_file(path).unwrap().unwrap();
+        assert_eq!(fs::read_to_string(&backup).unwrap(), V1);
+        let (version, _) = upgraded(&fs::read_to_string(path).unwrap()).unwrap();
+        assert_eq!(version, CONFIG_VERSION);
+        assert_eq!(upgrade_file(path).unwrap(), None);
+
+        // A second upgrade from version 1 would overwrite the first backup.
+        fs::write(path, V1).unwrap();
+        assert!(upgrade_file(path).is_err());
+        assert_eq!(fs::read_to_string(path).unwrap(), V1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
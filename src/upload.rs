@@ -1,14 +1,18 @@
-use std::path::Path;
+use std::fmt;
+use std::path::{Path, PathBuf};
 
 use actix_web::web;
-use anyhow::{anyhow, bail, Result};
+use anyhow::anyhow;
 use biliup::{
     client,
-    video::{BiliBili, Studio, Subtitle, Vid},
+    video::{BiliBili, Studio, Subtitle, Vid, Video},
     VideoFile,
 };
+use chrono::{DateTime, FixedOffset};
+use futures::future::{AbortRegistration, Abortable};
 use futures::StreamExt;
-use log::info;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
 
 use crate::{
     config::{ManagerConfig, RoomConfig},
@@ -16,7 +20,65 @@ use crate::{
     webhook::AppState,
 };
 
-use crate::db::BiliupDao;
+use crate::db::{BiliupDao, UploadStatus};
+
+/// The stage an upload failed at, used to decide whether to retry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorClass {
+    Config,
+    Login,
+    File,
+    Upload,
+    Submit,
+    Internal,
+    Cancelled,
+    /// The recording is outside of the recording directories.
+    Rejected,
+}
+
+#[derive(Debug)]
+pub struct UploadError {
+    pub class: ErrorClass,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for UploadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} error: {:#}", self.class, self.error)
+    }
+}
+
+impl std::error::Error for UploadError {}
+
+impl UploadError {
+    /// Whether the recording has not shown up on disk yet.
+    pub fn is_missing_file(&self) -> bool {
+        self.class == ErrorClass::File
+            && matches!(
+                self.error.downcast_ref::<std::io::Error>(),
+                Some(e) if e.kind() == std::io::ErrorKind::NotFound
+            )
+    }
+}
+
+pub type Result<T> = std::result::Result<T, UploadError>;
+
+trait Classify<T> {
+    fn class(self, class: ErrorClass) -> Result<T>;
+}
+
+impl<T, E> Classify<T> for std::result::Result<T, E>
+where
+    E: Into<anyhow::Error>,
+{
+    fn class(self, class: ErrorClass) -> Result<T> {
+        self.map_err(|e| UploadError {
+            class,
+            error: e.into(),
+        })
+    }
+}
 
 fn make_studio(data: &RecorderEventData, config: &RoomConfig) -> Studio {
     Studio {
@@ -29,7 +91,7 @@ fn make_studio(data: &RecorderEventData, config: &RoomConfig) -> Studio {
         desc: config.description.clone(),
         dynamic: "".to_string(),
         subtitle: Subtitle::default(),
-        tag: config.tags.clone(),
+        tag: config.tags.join(","),
         videos: Vec::new(),
         dtime: None,
         open_subtitle: true,
@@ -45,25 +107,28 @@ fn make_studio(data: &RecorderEventData, config: &RoomConfig) -> Studio {
     }
 }
 
+/// A submitted upload.
+pub struct Uploaded {
+    pub aid: u64,
+    /// The line the file went through, `None` if an earlier attempt uploaded it.
+    pub line: Option<String>,
+}
+
 pub async fn upload(
     config: &ManagerConfig,
     dao: &BiliupDao,
     event: &RecorderEvent,
     state: &web::Data<AppState>,
-) -> Result<u64> {
+    abort: AbortRegistration,
+) -> Result<Uploaded> {
     let data = &event.event_data;
     info!("New event <{}>: {}", data.room_id, event.event_id);
 
-    info!("Pre: Updating current state");
-    {
-        let mut current = state.current.write().unwrap();
-        *current = Some(event.event_id.clone());
-    }
-
     let room_config = config
         .rooms
         .get(&event.event_data.room_id)
-        .ok_or(anyhow!("Cannot find room <{}>", event.event_data.room_id))?;
+        .ok_or_else(|| anyhow!("Cannot find room <{}>", event.event_data.room_id))
+        .class(ErrorClass::Config)?;
 
     info!("Create client and login");
     let client = client::Client::default();
@@ -71,83 +136,334 @@ pub async fn upload(
         let cookies_file = std::fs::File::options()
             .read(true)
             .write(true)
-            .open(&room_config.user_cookie)?;
-        client.login_by_cookies(cookies_file).await?
+            .open(&room_config.user_cookie)
+            .class(ErrorClass::Config)?;
+        client
+            .login_by_cookies(cookies_file)
+            .await
+            .map_err(|e| state.metrics.api_error("login", e))
+            .class(ErrorClass::Login)?
     };
+    state.metrics.update_cookie_expiry(&room_config.user_cookie);
 
-    info!("Upload video file");
-    let mut video = {
-        info!("File information");
-        let video_file = {
-            let video_path = Path::new(config.rec_dir.as_str()).join(&data.relative_path);
-            VideoFile::new(&video_path)?
-        };
-
-        info!("Create uploader");
-        let line = match config.line.as_str() {
-            "bda2" => biliup::line::bda2(),
-            "kodo" => biliup::line::kodo(),
-            "ws" => biliup::line::ws(),
-            "qn" => biliup::line::qn(),
-            "cos" => biliup::line::cos(),
-            "cos-internal" => biliup::line::cos_internal(),
-            "AUTO" => biliup::line::Probe::probe().await?,
-            _ => bail!("Unknown line: {}", config.line),
-        };
-        let uploader = line.to_uploader(video_file);
-
-        info!("Uploading {}", data.relative_path);
-        uploader
-            .upload(&client, config.limit, |vs| {
-                vs.map(|chunk| {
-                    let (chunk, len) = chunk?;
-                    let mut uploaded = state.uploaded.write().unwrap();
-                    *uploaded += len;
-                    Ok((chunk, len))
-                })
-            })
-            .await?
+    let previous = dao
+        .get_uploaded_video(&event.event_id)
+        .await
+        .class(ErrorClass::Internal)?;
+    let (video, line) = match previous {
+        Some(video) => {
+            info!("Reusing uploaded file {}", video.filename);
+            (video, None)
+        }
+        None => {
+            info!("Upload video file");
+            let (mut video, line) = upload_file(config, dao, event, &client, state, abort).await?;
+            video.title = Some(data.format(&room_config.part_title));
+            dao.set_uploaded_video(&event.event_id, &video)
+                .await
+                .class(ErrorClass::Internal)?;
+            (video, Some(line))
+        }
     };
-    video.title = Some(data.format(&room_config.part_title));
-    let mut uploaded_videos = vec![video];
+    set_status(dao, state, event, UploadStatus::Uploaded).await?;
 
+    let submission_lock = state.submission_lock(data.room_id);
+    let _submitting = submission_lock.lock().await;
     info!("Submit video");
-    let (studio_title, ret) = match dao.find_existing_upload(data).await? {
+    set_status(dao, state, event, UploadStatus::Submitting).await?;
+    let session_gap = chrono::Duration::seconds(config.session_gap as i64);
+    let submitted = dao
+        .get_submitted_archive(&event.event_id)
+        .await
+        .class(ErrorClass::Internal)?;
+    let existing = match submitted {
+        Some(aid) => Some(aid),
+        None => dao
+            .find_existing_upload(data, session_gap)
+            .await
+            .class(ErrorClass::Internal)?,
+    };
+    let (studio_title, aid, bvid) = match existing {
         Some(aid) => {
-            info!("Appending to av{}", aid);
             let mut studio = BiliBili::new(&login_info, &client)
                 .studio_data(Vid::Aid(aid))
-                .await?;
-            studio.videos.append(&mut uploaded_videos);
-            (studio.title.clone(), studio.edit(&login_info).await?)
+                .await
+                .map_err(|e| state.metrics.api_error("studio_data", e))
+                .class(ErrorClass::Submit)?;
+
+            // A previous attempt may have failed after the edit went through.
+            if studio.videos.iter().any(|v| v.filename == video.filename) {
+                info!("av{} already contains {}", aid, video.filename);
+            } else {
+                info!("Appending to av{}", aid);
+                let parts = dao
+                    .get_archive_parts(aid)
+                    .await
+                    .class(ErrorClass::Internal)?;
+                studio.videos.push(video);
+                sort_parts(&mut studio.videos, &parts, data);
+                studio
+                    .edit(&login_info)
+                    .await
+                    .map_err(|e| state.metrics.api_error("edit", e))
+                    .class(ErrorClass::Submit)?;
+            }
+            (studio.title.clone(), aid, None)
         }
         None => {
             let mut studio = make_studio(data, room_config);
 
             if !studio.cover.starts_with("http") {
+                let cover = std::fs::read(Path::new(&studio.cover)).class(ErrorClass::Config)?;
                 let cover_url = BiliBili::new(&login_info, &client)
-                    .cover_up(&std::fs::read(Path::new(&studio.cover))?)
-                    .await?;
+                    .cover_up(&cover)
+                    .await
+                    .map_err(|e| state.metrics.api_error("cover_up", e))
+                    .class(ErrorClass::Submit)?;
                 studio.cover = cover_url;
             }
 
             info!("Submitting a new archive: {}", studio.title);
-            studio.videos = uploaded_videos;
-            (studio.title.clone(), studio.submit(&login_info).await?)
+            studio.videos = vec![video];
+            let ret = studio
+                .submit(&login_info)
+                .await
+                .map_err(|e| state.metrics.api_error("submit", e))
+                .class(ErrorClass::Submit)?;
+            let aid = ret["data"]["aid"]
+                .as_u64()
+                .ok_or_else(|| anyhow!("Unexpected submit response: {}", ret))
+                .class(ErrorClass::Submit)?;
+            let bvid = ret["data"]["bvid"].as_str().map(str::to_string);
+            dao.set_submitted_archive(&event.event_id, aid, bvid.as_deref())
+                .await
+                .class(ErrorClass::Internal)?;
+            (studio.title.clone(), aid, bvid)
         }
     };
-    let aid = ret["data"]["aid"].as_u64().unwrap();
 
     info!("Uploading finished: av{}", aid);
-    dao.finish_upload(&event.event_id, aid, &studio_title)
-        .await?;
+    dao.finish_upload(&event.event_id, aid, bvid.as_deref(), &studio_title)
+        .await
+        .class(ErrorClass::Internal)?;
+    state.publish_status(&event.event_id, UploadStatus::Submitted);
+
+    Ok(Uploaded { aid, line })
+}
+
+/// Order the parts of an archive by recording time. The last part is the
+/// one being added; parts not uploaded by this manager stay in front.
+fn sort_parts(
+    videos: &mut [Video],
+    parts: &[(Video, DateTime<FixedOffset>)],
+    data: &RecorderEventData,
+) {
+    let new_part = videos.last().map(|v| v.filename.clone());
+    let new_time = data.time_range().map(|(start, _)| start);
+
+    videos.sort_by_key(|video| {
+        if Some(&video.filename) == new_part.as_ref() {
+            return new_time;
+        }
+        parts
+            .iter()
+            .find(|(part, _)| part.filename == video.filename)
+            .map(|(_, time)| *time)
+    });
+}
+
+async fn set_status(
+    dao: &BiliupDao,
+    state: &AppState,
+    event: &RecorderEvent,
+    status: UploadStatus,
+) -> Result<()> {
+    if !state.set_phase(&event.event_id, status) {
+        return Err(anyhow!("Cancelled")).class(ErrorClass::Cancelled);
+    }
+    dao.set_status(&event.event_id, status)
+        .await
+        .class(ErrorClass::Internal)?;
+    state.publish_status(&event.event_id, status);
+    Ok(())
+}
+
+/// Resolve the recorded file, following links, and make sure that it stays
+/// within the recording directories.
+fn recording_path(config: &ManagerConfig, data: &RecorderEventData) -> Result<PathBuf> {
+    data.check_relative_path().class(ErrorClass::Rejected)?;
+
+    let path = Path::new(&config.rec_dir).join(&data.relative_path);
+    let resolved = path.canonicalize().class(ErrorClass::File)?;
+
+    let allowed = std::iter::once(&config.rec_dir)
+        .chain(&config.extra_rec_dirs)
+        .filter_map(|dir| Path::new(dir).canonicalize().ok())
+        .any(|dir| resolved.starts_with(dir));
+    if !allowed {
+        return Err(anyhow!(
+            "{} resolves to {}, outside of the recording directories",
+            data.relative_path,
+            resolved.display()
+        ))
+        .class(ErrorClass::Rejected);
+    }
+
+    Ok(resolved)
+}
+
+async fn upload_file(
+    config: &ManagerConfig,
+    dao: &BiliupDao,
+    event: &RecorderEvent,
+    client: &client::Client,
+    state: &web::Data<AppState>,
+    abort: AbortRegistration,
+) -> Result<(Video, String)> {
+    let data = &event.event_data;
+
+    info!("File information");
+    let video_path = recording_path(config, data)?;
+    VideoFile::new(&video_path).class(ErrorClass::File)?;
+
+    info!("Uploading {}", data.relative_path);
+    set_status(dao, state, event, UploadStatus::Uploading).await?;
+
+    // Lines are tried in order, the next one only if the previous one failed.
+    let upload = async {
+        let mut last_error = None;
+        for (i, line) in config.line.iter().enumerate() {
+            if i > 0 {
+                state.restart_progress(&event.event_id);
+            }
+            match upload_through(config, line, &video_path, event, client, state).await {
+                Ok(video) => return Ok((video, line.clone())),
+                Err(e) => {
+                    warn!(
+                        "Upload of {} through {} failed: {}",
+                        event.event_id, line, e
+                    );
+                    state
+                        .metrics
+                        .uploads
+                        .with_label_values(&[&data.room_id.to_string(), line, "failed"])
+                        .inc();
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or(UploadError {
+            class: ErrorClass::Config,
+            error: anyhow!("No upload line configured"),
+        }))
+    };
+    Abortable::new(upload, abort)
+        .await
+        .map_err(|_| anyhow!("Cancelled"))
+        .class(ErrorClass::Cancelled)?
+}
+
+async fn upload_through(
+    config: &ManagerConfig,
+    line: &str,
+    video_path: &Path,
+    event: &RecorderEvent,
+    client: &client::Client,
+    state: &web::Data<AppState>,
+) -> Result<Video> {
+    info!("Create uploader for line {}", line);
+    let line = match line {
+        "bda2" => biliup::line::bda2(),
+        "kodo" => biliup::line::kodo(),
+        "ws" => biliup::line::ws(),
+        "qn" => biliup::line::qn(),
+        "cos" => biliup::line::cos(),
+        "cos-internal" => biliup::line::cos_internal(),
+        "AUTO" => biliup::line::Probe::probe()
+            .await
+            .class(ErrorClass::Upload)?,
+        _ => return Err(anyhow!("Unknown line: {}", line)).class(ErrorClass::Config),
+    };
+    let video_file = VideoFile::new(video_path).class(ErrorClass::File)?;
+    let uploader = line.to_uploader(video_file);
+
+    uploader
+        .upload(client, config.limit, |vs| {
+            vs.map(|chunk| {
+                let (chunk, len) = chunk?;
+                state.add_progress(&event.event_id, len);
+                Ok((chunk, len))
+            })
+        })
+        .await
+        .class(ErrorClass::Upload)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::test_util::{self, event_data as data, temp_dir};
+
+    fn config(rec_dir: &Path) -> ManagerConfig {
+        let mut config = test_util::config("");
+        config.rec_dir = rec_dir.display().to_string();
+        config
+    }
+
+    #[test]
+    fn resolves_recordings() {
+        let dir = temp_dir("resolves");
+        fs::create_dir(dir.join("rec")).unwrap();
+        fs::write(dir.join("rec/a.flv"), b"").unwrap();
+
+        let path = recording_path(&config(&dir.join("rec")), &data("a.flv")).unwrap();
+        assert_eq!(path, dir.join("rec/a.flv").canonicalize().unwrap());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rejects_invalid_relative_paths() {
+        let dir = temp_dir("invalid");
+        for path in ["", "/etc/passwd", "../a.flv"] {
+            let error = recording_path(&config(&dir), &data(path)).unwrap_err();
+            assert_eq!(error.class, ErrorClass::Rejected, "{:?}", path);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rejects_links_out_of_rec_dir() {
+        let dir = temp_dir("links");
+        fs::create_dir(dir.join("rec")).unwrap();
+        fs::write(dir.join("secret"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.join("secret"), dir.join("rec/a.flv")).unwrap();
+        std::os::unix::fs::symlink(&dir, dir.join("rec/up")).unwrap();
+
+        let config = config(&dir.join("rec"));
+        for path in ["a.flv", "up/secret"] {
+            let error = recording_path(&config, &data(path)).unwrap_err();
+            assert_eq!(error.class, ErrorClass::Rejected, "{:?}", path);
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
 
-    {
-        let mut uploaded = state.uploaded.write().unwrap();
-        let mut current = state.current.write().unwrap();
+    #[cfg(unix)]
+    #[test]
+    fn follows_links_into_extra_rec_dirs() {
+        let dir = temp_dir("extra");
+        fs::create_dir(dir.join("rec")).unwrap();
+        fs::create_dir(dir.join("extra")).unwrap();
+        fs::write(dir.join("extra/a.flv"), b"").unwrap();
+        std::os::unix::fs::symlink(dir.join("extra/a.flv"), dir.join("rec/a.flv")).unwrap();
 
-        *uploaded = 0;
-        *current = None;
+        let mut config = config(&dir.join("rec"));
+        config
+            .extra_rec_dirs
+            .push(dir.join("extra").display().to_string());
+        assert!(recording_path(&config, &data("a.flv")).is_ok());
+        fs::remove_dir_all(dir).unwrap();
     }
-    Ok(aid)
 }
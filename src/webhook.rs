@@ -1,160 +1,960 @@
-use std::sync::RwLock;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::sync::{Arc, Mutex, RwLock};
 
-use actix_web::{get, post, web, Responder};
-use chrono::NaiveDateTime;
+use actix_web::{get, post, web, HttpRequest, HttpResponse};
+use chrono::{NaiveDateTime, Utc};
+use futures::future::AbortHandle;
 use futures::StreamExt;
-use log::{debug, info};
-use serde::Serialize;
-use tokio::sync::mpsc;
+use log::{debug, info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
 
-use crate::db::BiliupDao;
-use crate::recorder::RecorderEvent;
-
-pub(crate) type RecorderEventSender = mpsc::Sender<RecorderEvent>;
+use crate::adapter::{self, FileEventError, RecorderAdapter};
+use crate::auth::{self, Admin, Reader, Recorder, Subscriber};
+use crate::config::{ManagerConfig, SharedConfig};
+use crate::db::{BiliupDao, HistoryFilter, UploadStatus};
+use crate::error::{ApiError, ApiResult};
+use crate::live::{Live, LiveEvent};
+use crate::metrics::Metrics;
+use crate::progress::Progress;
+use crate::recorder::RecorderPayload;
 
 #[derive(Debug, Default)]
 pub struct AppState {
-    pub(crate) current: RwLock<Option<String>>,
-    pub(crate) uploaded: RwLock<usize>,
+    /// Uploads currently handled by a worker, keyed by event ID.
+    pub(crate) jobs: RwLock<HashMap<String, JobState>>,
+    /// Rooms that no new upload is started for, until resumed. A copy of the
+    /// database, see [`AppState::reload_paused`].
+    pub(crate) paused: RwLock<Paused>,
+    pub(crate) queue: Notify,
+    /// Held while an upload of the room is submitted, see
+    /// [`AppState::submission_lock`].
+    submissions: Mutex<HashMap<u64, Arc<tokio::sync::Mutex<()>>>>,
+    pub(crate) metrics: Metrics,
+    /// Updates for clients of `/events`.
+    pub(crate) live: Live,
+}
+
+#[derive(Debug, Default, Clone, Serialize)]
+pub(crate) struct Paused {
+    pub(crate) all: bool,
+    pub(crate) rooms: BTreeSet<u64>,
+}
+
+impl Paused {
+    pub(crate) fn contains(&self, room_id: u64) -> bool {
+        self.all || self.rooms.contains(&room_id)
+    }
+}
+
+impl AppState {
+    pub(crate) fn add_progress(&self, event_id: &str, len: usize) {
+        if let Some(job) = self.jobs.write().unwrap().get_mut(event_id) {
+            job.progress.add_chunk(len);
+            self.metrics
+                .bytes_uploaded
+                .with_label_values(&[job.room_id.to_string().as_str()])
+                .inc_by(len as u64);
+            self.live.send(LiveEvent::Progress {
+                event_id: event_id.to_string(),
+                bytes_sent: job.progress.bytes_sent,
+                total: job.progress.total,
+                speed: job.progress.speed,
+                eta: job.progress.eta,
+            });
+        }
+    }
+
+    /// Parts of a room may go to the same archive, and of two edits of an
+    /// archive at once, the second drops the part added by the first. So
+    /// files of a room upload in parallel but are submitted one at a time.
+    pub(crate) fn submission_lock(&self, room_id: u64) -> Arc<tokio::sync::Mutex<()>> {
+        self.submissions
+            .lock()
+            .unwrap()
+            .entry(room_id)
+            .or_default()
+            .clone()
+    }
+
+    /// Start counting from zero again, when the file is uploaded anew.
+    pub(crate) fn restart_progress(&self, event_id: &str) {
+        if let Some(job) = self.jobs.write().unwrap().get_mut(event_id) {
+            let phase = job.progress.phase;
+            job.progress = Progress::new(job.progress.total);
+            job.progress.set_phase(phase);
+        }
+    }
+
+    /// Read the paused rooms from the database, where they outlive restarts.
+    pub async fn reload_paused(&self, dao: &BiliupDao) -> anyhow::Result<()> {
+        *self.paused.write().unwrap() = dao.get_paused().await?;
+        Ok(())
+    }
+
+    /// Tell clients of `/events` about a status change stored in the database.
+    pub(crate) fn publish_status(&self, event_id: &str, to: UploadStatus) {
+        self.live.send(LiveEvent::Status {
+            event_id: event_id.to_string(),
+            status: to,
+        });
+    }
+
+    /// Returns `false` without changing the phase if the job is cancelled.
+    pub(crate) fn set_phase(&self, event_id: &str, phase: UploadStatus) -> bool {
+        match self.jobs.write().unwrap().get_mut(event_id) {
+            Some(job) if job.cancelled => false,
+            Some(job) => {
+                job.progress.set_phase(phase);
+                true
+            }
+            None => true,
+        }
+    }
+
+    /// Cancel a running job, unless it is being submitted already.
+    /// Returns the phase of the job, or `None` if it is not running.
+    pub(crate) fn cancel(&self, event_id: &str) -> Option<UploadStatus> {
+        let mut jobs = self.jobs.write().unwrap();
+        let job = jobs.get_mut(event_id)?;
+
+        if job.progress.phase != UploadStatus::Submitting {
+            job.cancelled = true;
+            job.abort.abort();
+        }
+
+        Some(job.progress.phase)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct JobState {
+    pub(crate) event_id: String,
+    pub(crate) room_id: u64,
+
+    /// Cookie file of the account uploading this job.
+    #[serde(skip)]
+    pub(crate) account: Option<String>,
+
+    #[serde(serialize_with = "dt_to_ts")]
+    pub(crate) started_at: chrono::NaiveDateTime,
+
+    pub(crate) cancelled: bool,
+
+    /// Aborts the transfer of the video file.
+    #[serde(skip)]
+    pub(crate) abort: AbortHandle,
+
+    #[serde(flatten)]
+    pub(crate) progress: Progress,
 }
 
 #[derive(Debug, Serialize)]
 pub(crate) struct UploadState {
     pub(crate) event_id: String,
+    pub(crate) status: UploadStatus,
 
     #[serde(serialize_with = "dt_to_ts")]
     pub(crate) created_at: chrono::NaiveDateTime,
 
+    #[serde(serialize_with = "dt_to_ts")]
+    pub(crate) updated_at: chrono::NaiveDateTime,
+
     pub(crate) relative_path: String,
     pub(crate) file_size: i64,
+
+    pub(crate) attempts: i64,
+    pub(crate) last_error: Option<String>,
+
+    #[serde(serialize_with = "some_dt_to_ts")]
+    pub(crate) next_attempt_at: Option<chrono::NaiveDateTime>,
+
+    pub(crate) priority: i64,
 }
 
 #[derive(Debug, Serialize)]
 pub(crate) struct UploadHistory {
+    pub(crate) event_id: String,
+    pub(crate) room_id: i64,
+    pub(crate) name: String,
+    /// Title of the stream.
+    pub(crate) title: String,
+    pub(crate) status: UploadStatus,
+
+    #[serde(serialize_with = "dt_to_ts")]
+    pub(crate) created_at: chrono::NaiveDateTime,
     #[serde(serialize_with = "some_dt_to_ts")]
     pub(crate) finished_at: Option<chrono::NaiveDateTime>,
 
     pub(crate) relative_path: String,
     pub(crate) file_size: i64,
+    /// Seconds.
+    pub(crate) duration: f32,
 
     pub(crate) avid: Option<i64>,
+    pub(crate) bvid: Option<String>,
+    pub(crate) archive_title: Option<String>,
+    pub(crate) part_title: Option<String>,
+}
+
+const HISTORY_LIMIT: i64 = 50;
+const HISTORY_MAX_LIMIT: i64 = 500;
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct HistoryQuery {
+    pub room_id: Option<u64>,
+    /// Unix timestamps bounding when the uploads were created.
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub status: Option<UploadStatus>,
+    /// Text to look for in the titles.
+    pub q: Option<String>,
+    pub limit: Option<i64>,
+    #[serde(default)]
+    pub offset: i64,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct HistoryResponse {
+    /// Number of uploads matching the query, regardless of pagination.
+    pub total: i64,
+    pub limit: i64,
+    pub offset: i64,
+    pub uploads: Vec<UploadHistory>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct UploadTransition {
+    pub(crate) status: UploadStatus,
+
+    #[serde(serialize_with = "dt_to_ts")]
+    pub(crate) created_at: chrono::NaiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct UploadResponse {
+    pub event_id: String,
+    pub status: UploadStatus,
+    pub transitions: Vec<UploadTransition>,
+}
+
+/// A room of the configuration, without the login cookie.
+#[derive(Debug, Serialize)]
+pub(crate) struct RoomResponse {
+    pub room_id: u64,
+    pub studio_title: String,
+    pub part_title: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub tid: u16,
+    pub profile: Option<String>,
+    pub paused: bool,
+}
+
+/// The config of a room after inheriting, without the login cookie.
+#[derive(Debug, Serialize)]
+pub(crate) struct RoomConfigResponse {
+    pub room_id: u64,
+    pub profile: Option<String>,
+    /// Fields taken from the profile or `defaults`, and which one.
+    pub inherited: BTreeMap<String, String>,
+    pub studio_title: String,
+    pub part_title: String,
+    pub cover: String,
+    pub description: String,
+    pub tags: Vec<String>,
+    pub tid: u16,
 }
 
 #[derive(Debug, Serialize)]
 pub(crate) struct StateResponse {
-    pub current: Option<String>,
-    pub uploaded: usize,
+    pub jobs: Vec<JobState>,
+    pub paused: Paused,
     pub uploads: Vec<UploadState>,
 }
 
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum IngestResult {
+    /// A new upload was queued.
+    Queued,
+    /// The event was stored, nothing to upload.
+    Accepted,
+    /// The event is of no interest, e.g. a recording of a few seconds.
+    Ignored,
+    /// The event was delivered before.
+    Duplicate,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct IngestResponse {
+    pub result: IngestResult,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upload_status: Option<UploadStatus>,
+}
+
+impl IngestResponse {
+    fn new(result: IngestResult) -> Self {
+        Self {
+            result,
+            upload_status: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RetryResponse {
+    pub event_id: String,
+    pub status: UploadStatus,
+    /// `false` if the upload was already queued or running.
+    pub requeued: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RetryOptions {
+    /// Jump ahead of uploads queued without priority.
+    #[serde(default)]
+    pub priority: bool,
+}
+
+impl RetryOptions {
+    fn priority(&self) -> i64 {
+        if self.priority {
+            1
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct RetryFilter {
+    pub room_id: Option<u64>,
+    /// Unix timestamps bounding when the uploads were created.
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    #[serde(flatten)]
+    pub options: RetryOptions,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct RetryAllResponse {
+    pub event_ids: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub(crate) struct JobResponse {
+    pub event_id: String,
+    pub status: UploadStatus,
+}
+
+#[derive(Debug, Deserialize)]
+pub(crate) struct PriorityRequest {
+    /// Higher goes first, 0 by default.
+    pub priority: i64,
+}
+
+/// Register the API. It is served under `/api/v1`, and at the root for
+/// compatibility with older clients.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(status)
+        .service(status_ok)
+        .service(list_rooms)
+        .service(room_config)
+        .service(upload_status)
+        .service(recorder)
+        .service(recorder_with)
+        .service(retry)
+        .service(retry_all)
+        .service(cancel)
+        .service(set_priority)
+        .service(pause)
+        .service(resume)
+        .service(pause_room)
+        .service(resume_room)
+        .service(export_metrics)
+        .service(live_events);
+}
+
 #[get("/stat")]
 pub(crate) async fn status(
+    _: Reader,
     state: web::Data<AppState>,
     dao: web::Data<BiliupDao>,
-) -> impl Responder {
+) -> ApiResult<web::Json<StateResponse>> {
     debug!("Received status request");
 
-    let uploads = match dao.get_unfinished_uploads().await {
-        Ok(uploads) => uploads,
-        Err(_) => return web::Json(None),
-    };
+    let uploads = dao.get_unfinished_uploads().await?;
+    let mut jobs: Vec<JobState> = state.jobs.read().unwrap().values().cloned().collect();
+    jobs.sort_by_key(|job| job.started_at);
+
     let response = StateResponse {
-        current: (*state.current.read().unwrap()).clone(),
-        uploaded: *state.uploaded.read().unwrap(),
+        jobs,
+        paused: state.paused.read().unwrap().clone(),
         uploads,
     };
 
-    web::Json(Some(response))
+    Ok(web::Json(response))
+}
+
+#[get("/events")]
+pub(crate) async fn live_events(_: Subscriber, state: web::Data<AppState>) -> HttpResponse {
+    debug!("New client of the event stream");
+
+    HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(state.live.subscribe())
+}
+
+#[get("/metrics")]
+pub(crate) async fn export_metrics(
+    _: Reader,
+    state: web::Data<AppState>,
+    dao: web::Data<BiliupDao>,
+    config: web::Data<SharedConfig>,
+) -> ApiResult<HttpResponse> {
+    let metrics = &state.metrics;
+
+    metrics.uploads_by_status.reset();
+    for (kind, count) in dao.count_uploads_by_status().await? {
+        metrics
+            .uploads_by_status
+            .with_label_values(&[kind.as_str()])
+            .set(count);
+    }
+    for room in config.load().rooms.values() {
+        metrics.update_cookie_expiry(&room.user_cookie);
+    }
+
+    let body = metrics.render().map_err(anyhow::Error::from)?;
+    Ok(HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(body))
 }
 
 #[get("/history")]
-pub(crate) async fn status_ok(dao: web::Data<BiliupDao>) -> impl Responder {
-    debug!("Received history request");
+pub(crate) async fn status_ok(
+    _: Reader,
+    dao: web::Data<BiliupDao>,
+    query: web::Query<HistoryQuery>,
+) -> ApiResult<web::Json<HistoryResponse>> {
+    debug!("Received history request: {:?}", query);
+
+    let limit = query.limit.unwrap_or(HISTORY_LIMIT);
+    if !(1..=HISTORY_MAX_LIMIT).contains(&limit) {
+        return Err(ApiError::bad_request(
+            "bad_limit",
+            format!("limit must be between 1 and {}", HISTORY_MAX_LIMIT),
+        ));
+    }
+    if query.offset < 0 {
+        return Err(ApiError::bad_request(
+            "bad_offset",
+            "offset must not be negative",
+        ));
+    }
 
-    let uploads = match dao.get_finished_uploads().await {
-        Ok(uploads) => Some(uploads),
-        Err(_) => None,
+    let filter = HistoryFilter {
+        room_id: query.room_id,
+        from: ts_to_dt(query.from)?,
+        to: ts_to_dt(query.to)?,
+        status: query.status,
+        search: query.q.clone().filter(|q| !q.is_empty()),
+        limit,
+        offset: query.offset,
     };
+    let (total, uploads) = dao.get_upload_history(&filter).await?;
 
-    web::Json(uploads)
+    Ok(web::Json(HistoryResponse {
+        total,
+        limit,
+        offset: query.offset,
+        uploads,
+    }))
+}
+
+#[get("/rooms")]
+pub(crate) async fn list_rooms(
+    _: Reader,
+    config: web::Data<SharedConfig>,
+    state: web::Data<AppState>,
+) -> web::Json<Vec<RoomResponse>> {
+    let paused = state.paused.read().unwrap();
+
+    let mut rooms: Vec<RoomResponse> = config
+        .load()
+        .rooms
+        .values()
+        .map(|room| RoomResponse {
+            room_id: room.room_id,
+            studio_title: room.studio_title.clone(),
+            part_title: room.part_title.clone(),
+            description: room.description.clone(),
+            tags: room.tags.clone(),
+            tid: room.tid,
+            profile: room.profile.clone(),
+            paused: paused.contains(room.room_id),
+        })
+        .collect();
+    rooms.sort_by_key(|room| room.room_id);
+
+    web::Json(rooms)
+}
+
+/// The config of a room as used for uploads, after inheriting from its
+/// profile and `defaults`. The login cookie is left out, as read tokens
+/// must not lead to the account.
+#[get("/rooms/{room_id}/config")]
+pub(crate) async fn room_config(
+    _: Reader,
+    config: web::Data<SharedConfig>,
+    path: web::Path<(u64,)>,
+) -> ApiResult<web::Json<RoomConfigResponse>> {
+    let room_id = path.0;
+    debug!("Received room config request: {}", room_id);
+
+    let config = config.load();
+    let room = config.rooms.get(&room_id).ok_or_else(|| {
+        ApiError::not_found(
+            "no_such_room",
+            format!("Room {} is not configured", room_id),
+        )
+    })?;
+
+    Ok(web::Json(RoomConfigResponse {
+        room_id: room.room_id,
+        profile: room.profile.clone(),
+        inherited: room.inherited.clone(),
+        studio_title: room.studio_title.clone(),
+        part_title: room.part_title.clone(),
+        cover: room.cover.clone(),
+        description: room.description.clone(),
+        tags: room.tags.clone(),
+        tid: room.tid,
+    }))
+}
+
+#[get("/upload/{event_id}")]
+pub(crate) async fn upload_status(
+    _: Reader,
+    dao: web::Data<BiliupDao>,
+    path: web::Path<(String,)>,
+) -> ApiResult<web::Json<UploadResponse>> {
+    let event_id = &path.0;
+    debug!("Received upload status request: {}", event_id);
+
+    let current = dao.get_status(event_id).await?.ok_or_else(|| {
+        ApiError::not_found(
+            "no_such_upload",
+            format!("No upload for event {}", event_id),
+        )
+    })?;
+    let transitions = dao.get_transitions(event_id).await?;
+
+    Ok(web::Json(UploadResponse {
+        event_id: event_id.clone(),
+        status: current,
+        transitions,
+    }))
 }
 
 #[post("/recorder")]
 pub(crate) async fn recorder(
-    mut payload: web::Payload,
-    tx: web::Data<RecorderEventSender>,
+    _: Recorder,
+    req: HttpRequest,
+    payload: web::Payload,
+    dao: web::Data<BiliupDao>,
+    state: web::Data<AppState>,
+    config: web::Data<SharedConfig>,
+) -> ApiResult<web::Json<IngestResponse>> {
+    let payload = read_payload(&req, payload).await?;
+
+    let adapter = adapter::sniff(&payload).ok_or_else(|| {
+        ApiError::bad_request("unknown_recorder", "Payload of an unknown recorder")
+    })?;
+
+    ingest(adapter, payload, &dao, &state, config.load_full())
+        .await
+        .map(web::Json)
+}
+
+#[post("/recorder/{adapter}")]
+pub(crate) async fn recorder_with(
+    _: Recorder,
+    req: HttpRequest,
+    path: web::Path<(String,)>,
+    payload: web::Payload,
     dao: web::Data<BiliupDao>,
-) -> &'static str {
-    let event = {
-        let mut body = web::BytesMut::new();
-        while let Some(chunk) = payload.next().await {
-            let chunk = match chunk {
-                Ok(chunk) => chunk,
-                Err(_) => return "Failed to read payload",
-            };
-            // limit max size of in-memory payload
-            body.extend_from_slice(&chunk);
-        }
-
-        match serde_json::from_slice::<RecorderEvent>(&body) {
-            Ok(event) => event,
-            Err(_) => return "OK",
+    state: web::Data<AppState>,
+    config: web::Data<SharedConfig>,
+) -> ApiResult<web::Json<IngestResponse>> {
+    let adapter = adapter::by_name(&path.0).ok_or_else(|| {
+        ApiError::not_found("no_such_recorder", format!("Unknown recorder {}", path.0))
+    })?;
+
+    let payload = read_payload(&req, payload).await?;
+
+    ingest(adapter, payload, &dao, &state, config.load_full())
+        .await
+        .map(web::Json)
+}
+
+async fn read_payload(
+    req: &HttpRequest,
+    mut payload: web::Payload,
+) -> ApiResult<serde_json::Value> {
+    let mut body = web::BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk.map_err(|e| ApiError::bad_request("bad_payload", e.to_string()))?;
+        // limit max size of in-memory payload
+        body.extend_from_slice(&chunk);
+    }
+    auth::verify_signature(req, &body)?;
+
+    serde_json::from_slice(&body).map_err(|e| ApiError::bad_request("bad_payload", e.to_string()))
+}
+
+async fn ingest(
+    adapter: &'static dyn RecorderAdapter,
+    payload: serde_json::Value,
+    dao: &BiliupDao,
+    state: &AppState,
+    config: Arc<ManagerConfig>,
+) -> ApiResult<IngestResponse> {
+    let parsed = web::block(move || adapter.parse(payload, &config))
+        .await
+        .map_err(anyhow::Error::from)?;
+    let event = match parsed {
+        Ok(Some(event)) => event,
+        Ok(None) => return Ok(IngestResponse::new(IngestResult::Ignored)),
+        // The recording would be lost if acknowledged.
+        Err(e) if e.is::<FileEventError>() => {
+            warn!("Cannot handle {} file event: {:#}", adapter.name(), e);
+            return Err(ApiError::unprocessable("bad_file_event", e.to_string()));
+        }
+        Err(e) => {
+            warn!("Malformed {} payload: {:#}", adapter.name(), e);
+            return Err(ApiError::bad_request("bad_payload", format!("{:#}", e)));
         }
     };
-    info!("Received recorder event");
+    // Checked before anything is stored, so that the recorder hears about it.
+    if let RecorderPayload::FileClosed(data) = &event.payload {
+        if let Err(e) = data.check_relative_path() {
+            warn!("Rejected event {}: {}", event.event_id, e);
+            return Err(ApiError::bad_request("bad_path", e.to_string()));
+        }
+        if data.time_range().is_none() {
+            return Err(ApiError::bad_request(
+                "bad_file_open_time",
+                format!("Invalid FileOpenTime {:?}", data.file_open_time),
+            ));
+        }
+    }
+    // Redelivered file events go on, as the upload may not have been queued
+    // the first time; `add_event` tells whether it was.
+    let is_new = dao.add_recorder_event(&event).await?;
+    if !is_new && !matches!(event.payload, RecorderPayload::FileClosed(_)) {
+        debug!("Event {} was delivered before", event.event_id);
+        return Ok(IngestResponse::new(IngestResult::Duplicate));
+    }
 
-    if event.event_type != "FileClosed" {
-        return "OK";
+    info!(
+        "Received {} event <{}>: {}",
+        adapter.name(),
+        event.payload.room_id(),
+        event.payload.event_type()
+    );
+    if is_new {
+        state
+            .metrics
+            .events
+            .with_label_values(&[adapter.name(), event.payload.event_type().as_str()])
+            .inc();
+        state.live.send(LiveEvent::Recorder {
+            recorder: adapter.name(),
+            event_id: event.event_id.clone(),
+            event_type: event.payload.event_type(),
+            room_id: event.payload.room_id(),
+        });
     }
 
-    if event.event_data.duration < 10.0 {
-        return "OK";
+    let sent_at = event
+        .timestamp()
+        .map(|timestamp| timestamp.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now);
+    match &event.payload {
+        RecorderPayload::SessionStarted(data) => dao.start_session(data, sent_at).await?,
+        RecorderPayload::SessionEnded(data) => {
+            info!(
+                "Session {} of room <{}> ended",
+                data.session_id, data.room.room_id
+            );
+            dao.end_session(data, sent_at).await?
+        }
+        _ => (),
     }
 
-    match dao.add_event(&event).await {
-        Ok(_) => (),
-        Err(_) => return "Failed",
+    let mut event = match event.into_file_event() {
+        Some(event) => event,
+        None => return Ok(IngestResponse::new(IngestResult::Accepted)),
+    };
+
+    if !event.event_data.duration_unknown && event.event_data.duration < 10.0 {
+        return Ok(IngestResponse::new(IngestResult::Ignored));
     }
 
-    match dao.add_upload(&event).await {
-        Ok(_) => (),
-        Err(_) => return "Failed",
+    // Fill in what the recorder did not tell from the live session.
+    let data = &mut event.event_data;
+    if data.session_id.is_none() || data.name.is_empty() || data.title.is_empty() {
+        if let Some((session_id, name, title)) = dao.current_session(data.room_id).await? {
+            data.session_id.get_or_insert(session_id);
+            if data.name.is_empty() {
+                data.name = name;
+            }
+            if data.title.is_empty() {
+                data.title = title;
+            }
+        }
     }
 
-    match tx.send(event.clone()).await {
-        Ok(_) => "OK",
-        Err(_) => "Failed",
+    if let Some(existing) = dao.add_event(&event).await? {
+        return Ok(IngestResponse {
+            result: IngestResult::Duplicate,
+            upload_status: Some(existing),
+        });
     }
+
+    state.publish_status(&event.event_id, UploadStatus::Queued);
+    state.queue.notify_one();
+    Ok(IngestResponse {
+        result: IngestResult::Queued,
+        upload_status: Some(UploadStatus::Queued),
+    })
 }
 
 #[post("/retry/{event_id}")]
 pub(crate) async fn retry(
-    tx: web::Data<RecorderEventSender>,
+    _: Admin,
     dao: web::Data<BiliupDao>,
     path: web::Path<(String,)>,
+    options: web::Query<RetryOptions>,
     state: web::Data<AppState>,
-) -> &'static str {
-    let current = state.current.read().unwrap();
-    if (*current).is_some() {
-        return "Busy";
+) -> ApiResult<web::Json<RetryResponse>> {
+    let event_id = &path.0;
+    debug!("Retrying event: {}", event_id);
+
+    let current = dao.get_status(event_id).await?.ok_or_else(|| {
+        ApiError::not_found("no_such_event", format!("No such event {}", event_id))
+    })?;
+
+    let requeued = match current {
+        UploadStatus::Failed | UploadStatus::Cancelled => {
+            dao.requeue_upload(event_id, options.priority()).await?
+        }
+        UploadStatus::Queued
+        | UploadStatus::WaitingForFile
+        | UploadStatus::Uploading
+        | UploadStatus::Uploaded
+        | UploadStatus::Submitting => {
+            if options.priority {
+                dao.set_priority(event_id, options.priority()).await?;
+            }
+            false
+        }
+        _ => {
+            return Err(ApiError::conflict(
+                "not_retriable",
+                format!("{} is already submitted", event_id),
+            ))
+        }
+    };
+
+    if requeued {
+        state.publish_status(event_id, UploadStatus::Queued);
+        state.queue.notify_one();
     }
 
+    let current = dao.get_status(event_id).await?.unwrap_or(current);
+    Ok(web::Json(RetryResponse {
+        event_id: event_id.clone(),
+        status: current,
+        requeued,
+    }))
+}
+
+#[post("/retry")]
+pub(crate) async fn retry_all(
+    _: Admin,
+    dao: web::Data<BiliupDao>,
+    filter: web::Json<RetryFilter>,
+    state: web::Data<AppState>,
+) -> ApiResult<web::Json<RetryAllResponse>> {
+    debug!("Retrying failed uploads: {:?}", filter);
+
+    let event_ids = dao
+        .requeue_failed_uploads(
+            filter.room_id,
+            ts_to_dt(filter.from)?,
+            ts_to_dt(filter.to)?,
+            filter.options.priority(),
+        )
+        .await?;
+
+    if !event_ids.is_empty() {
+        info!("Re-enqueued {} failed uploads", event_ids.len());
+        for event_id in &event_ids {
+            state.publish_status(event_id, UploadStatus::Queued);
+        }
+        state.queue.notify_one();
+    }
+
+    Ok(web::Json(RetryAllResponse { event_ids }))
+}
+
+#[post("/cancel/{event_id}")]
+pub(crate) async fn cancel(
+    _: Admin,
+    dao: web::Data<BiliupDao>,
+    path: web::Path<(String,)>,
+    state: web::Data<AppState>,
+) -> ApiResult<web::Json<JobResponse>> {
     let event_id = &path.0;
-    let event = match dao.get_event(event_id).await {
-        Ok(event) => event,
-        Err(_) => return "Failed",
-    };
-    let event = match event {
-        Some(event) => event,
-        None => return "No such event",
+    info!("Cancelling {}", event_id);
+
+    let current = dao.get_status(event_id).await?.ok_or_else(|| {
+        ApiError::not_found("no_such_event", format!("No such event {}", event_id))
+    })?;
+
+    let too_late = || {
+        ApiError::conflict(
+            "not_cancellable",
+            format!("{} is already being submitted", event_id),
+        )
     };
 
-    debug!("Retrying event: {:?}", event);
+    // A running job is aborted by its worker, which then marks it cancelled.
+    // It cannot get past its current phase anymore, so it is reported as
+    // cancelled right away.
+    if let Some(phase) = state.cancel(event_id) {
+        if phase == UploadStatus::Submitting {
+            return Err(too_late());
+        }
+        return Ok(web::Json(JobResponse {
+            event_id: event_id.clone(),
+            status: UploadStatus::Cancelled,
+        }));
+    }
 
-    match tx.send(event).await {
-        Ok(_) => "OK",
-        Err(_) => "Failed",
+    match current {
+        UploadStatus::Cancelled => (),
+        UploadStatus::Queued
+        | UploadStatus::WaitingForFile
+        | UploadStatus::Uploaded
+        | UploadStatus::Failed => {
+            dao.set_status(event_id, UploadStatus::Cancelled).await?;
+            state.publish_status(event_id, UploadStatus::Cancelled);
+        }
+        _ => return Err(too_late()),
     }
+
+    Ok(web::Json(JobResponse {
+        event_id: event_id.clone(),
+        status: UploadStatus::Cancelled,
+    }))
+}
+
+#[post("/priority/{event_id}")]
+pub(crate) async fn set_priority(
+    _: Admin,
+    dao: web::Data<BiliupDao>,
+    path: web::Path<(String,)>,
+    request: web::Json<PriorityRequest>,
+    state: web::Data<AppState>,
+) -> ApiResult<web::Json<JobResponse>> {
+    let event_id = &path.0;
+    debug!("Setting priority of {} to {}", event_id, request.priority);
+
+    let current = dao.get_status(event_id).await?.ok_or_else(|| {
+        ApiError::not_found("no_such_event", format!("No such event {}", event_id))
+    })?;
+
+    dao.set_priority(event_id, request.priority).await?;
+    state.queue.notify_one();
+
+    Ok(web::Json(JobResponse {
+        event_id: event_id.clone(),
+        status: current,
+    }))
+}
+
+#[post("/pause")]
+pub(crate) async fn pause(
+    _: Admin,
+    dao: web::Data<BiliupDao>,
+    state: web::Data<AppState>,
+) -> ApiResult<web::Json<Paused>> {
+    info!("Pausing the queue");
+
+    dao.pause(None).await?;
+    state.reload_paused(&dao).await?;
+    Ok(web::Json(state.paused.read().unwrap().clone()))
+}
+
+#[post("/resume")]
+pub(crate) async fn resume(
+    _: Admin,
+    dao: web::Data<BiliupDao>,
+    state: web::Data<AppState>,
+) -> ApiResult<web::Json<Paused>> {
+    info!("Resuming the queue");
+
+    dao.resume(None).await?;
+    state.reload_paused(&dao).await?;
+    state.queue.notify_one();
+
+    Ok(web::Json(state.paused.read().unwrap().clone()))
+}
+
+#[post("/pause/{room_id}")]
+pub(crate) async fn pause_room(
+    _: Admin,
+    dao: web::Data<BiliupDao>,
+    path: web::Path<(u64,)>,
+    state: web::Data<AppState>,
+) -> ApiResult<web::Json<Paused>> {
+    info!("Pausing room <{}>", path.0);
+
+    dao.pause(Some(path.0)).await?;
+    state.reload_paused(&dao).await?;
+    Ok(web::Json(state.paused.read().unwrap().clone()))
+}
+
+#[post("/resume/{room_id}")]
+pub(crate) async fn resume_room(
+    _: Admin,
+    dao: web::Data<BiliupDao>,
+    path: web::Path<(u64,)>,
+    state: web::Data<AppState>,
+) -> ApiResult<web::Json<Paused>> {
+    info!("Resuming room <{}>", path.0);
+
+    dao.resume(Some(path.0)).await?;
+    state.reload_paused(&dao).await?;
+    state.queue.notify_one();
+
+    Ok(web::Json(state.paused.read().unwrap().clone()))
+}
+
+fn ts_to_dt(ts: Option<i64>) -> ApiResult<Option<chrono::DateTime<chrono::Utc>>> {
+    ts.map(|ts| {
+        NaiveDateTime::from_timestamp_opt(ts, 0)
+            .map(|dt| chrono::DateTime::from_utc(dt, chrono::Utc))
+            .ok_or_else(|| {
+                ApiError::bad_request("bad_timestamp", format!("Invalid timestamp {}", ts))
+            })
+    })
+    .transpose()
 }
 
 pub(crate) fn dt_to_ts<S>(dt: &NaiveDateTime, serializer: S) -> Result<S::Ok, S::Error>
@@ -168,5 +968,117 @@ pub(crate) fn some_dt_to_ts<S>(dt: &Option<NaiveDateTime>, serializer: S) -> Res
 where
     S: serde::Serializer,
 {
-    serializer.serialize_i64(dt.unwrap().timestamp())
+    match dt {
+        Some(dt) => serializer.serialize_i64(dt.timestamp()),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test, App};
+    use arc_swap::ArcSwap;
+    use serde_json::json;
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+    use crate::test_util;
+
+    async fn dao() -> web::Data<BiliupDao> {
+        // One connection, as every connection gets its own in-memory database.
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap();
+        sqlx::migrate!().run(&pool).await.unwrap();
+        web::Data::new(BiliupDao::new(pool))
+    }
+
+    async fn post(dao: &web::Data<BiliupDao>, body: &str) -> (StatusCode, serde_json::Value) {
+        let config = test_util::config("");
+        let app = test::init_service(
+            App::new()
+                .app_data(dao.clone())
+                .app_data(web::Data::new(AppState::default()))
+                .app_data(web::Data::new(ArcSwap::from_pointee(config)))
+                .configure(configure),
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/recorder")
+            .set_payload(body.to_string())
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        (res.status(), test::read_body_json(res).await)
+    }
+
+    fn file_closed(file_open_time: &str) -> String {
+        json!({
+            "EventType": "FileClosed",
+            "EventId": "file",
+            "EventData": {
+                "RoomId": 1, "Name": "name", "Title": "title", "RelativePath": "1/a.flv",
+                "FileOpenTime": file_open_time, "FileSize": 1024, "Duration": 60.0,
+            },
+        })
+        .to_string()
+    }
+
+    #[actix_web::test]
+    async fn rejects_malformed_payloads() {
+        let dao = dao().await;
+
+        let (code, body) = post(&dao, "{").await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "bad_payload");
+
+        let (code, body) = post(&dao, &file_closed("yesterday")).await;
+        assert_eq!(code, StatusCode::BAD_REQUEST);
+        assert_eq!(body["error"]["code"], "bad_file_open_time");
+        assert!(dao.get_event("file").await.unwrap().is_none());
+
+        let (code, body) = post(&dao, &file_closed("2021-06-01T20:00:00+08:00")).await;
+        assert_eq!(code, StatusCode::OK);
+        assert_eq!(body["result"], "queued");
+    }
+
+    #[actix_web::test]
+    async fn keeps_pauses_across_restarts() {
+        let dao = dao().await;
+        dao.pause(Some(3)).await.unwrap();
+        dao.pause(None).await.unwrap();
+
+        let state = AppState::default();
+        state.reload_paused(&dao).await.unwrap();
+        assert!(state.paused.read().unwrap().all);
+        assert!(state.paused.read().unwrap().rooms.contains(&3));
+
+        dao.resume(None).await.unwrap();
+        state.reload_paused(&dao).await.unwrap();
+        assert!(!state.paused.read().unwrap().contains(3));
+    }
+
+    #[actix_web::test]
+    async fn acknowledges_redelivered_events() {
+        let dao = dao().await;
+        let session_started = json!({
+            "EventType": "SessionStarted",
+            "EventId": "session",
+            "EventTimestamp": "2021-06-01T20:00:00+08:00",
+            "EventData": { "RoomId": 1, "Name": "name", "Title": "title", "SessionId": "s1" },
+        })
+        .to_string();
+
+        let (_, body) = post(&dao, &session_started).await;
+        assert_eq!(body["result"], "accepted");
+        let (_, body) = post(&dao, &session_started).await;
+        assert_eq!(body["result"], "duplicate");
+
+        let (_, body) = post(&dao, &file_closed("2021-06-01T20:00:00+08:00")).await;
+        assert_eq!(body["result"], "queued");
+        let (_, body) = post(&dao, &file_closed("2021-06-01T20:00:00+08:00")).await;
+        assert_eq!(body["result"], "duplicate");
+        assert_eq!(body["upload_status"], "queued");
+    }
 }
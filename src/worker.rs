@@ -0,0 +1,250 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use actix_web::web;
+use futures::future::AbortHandle;
+use log::{info, warn};
+
+use crate::{
+    config::{ManagerConfig, SharedConfig},
+    db::{BiliupDao, QueuedUpload, UploadStatus},
+    progress::Progress,
+    recorder::RecorderEvent,
+    upload::{self, ErrorClass, UploadError},
+    webhook::{AppState, JobState},
+};
+
+/// Drive uploads from the `uploads` table.
+///
+/// Webhooks only insert a row and wake the scheduler up, so anything queued
+/// or interrupted before a restart is picked up again here. Each upload runs
+/// in its own task, within the limits of `config.workers`. Every upload keeps
+/// the config as it was when the upload started.
+pub async fn run(config: Arc<SharedConfig>, dao: web::Data<BiliupDao>, state: web::Data<AppState>) {
+    match dao.requeue_unfinished_uploads().await {
+        Ok(0) => (),
+        Ok(n) => info!("Re-enqueued {} interrupted uploads", n),
+        Err(e) => warn!("Failed to re-enqueue interrupted uploads: {}", e),
+    }
+
+    let mut last_started = HashMap::new();
+
+    loop {
+        let candidates = match dao.queued_uploads().await {
+            Ok(candidates) => candidates,
+            Err(e) => {
+                warn!("Failed to fetch queued uploads: {}", e);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+                continue;
+            }
+        };
+
+        let config = config.load_full();
+        match pick(&config, &state, &candidates, &last_started) {
+            Some(upload) => {
+                let event = upload.event.clone();
+                last_started.insert(event.event_data.room_id, Instant::now());
+
+                let waited = chrono::Utc::now().naive_utc() - upload.queued_since;
+                state
+                    .metrics
+                    .time_in_queue
+                    .observe(waited.num_milliseconds().max(0) as f64 / 1000.0);
+
+                start(config.clone(), dao.clone(), state.clone(), event);
+            }
+            None => wait_for_work(&dao, &state).await,
+        }
+    }
+}
+
+fn account(config: &ManagerConfig, event: &RecorderEvent) -> Option<String> {
+    config
+        .rooms
+        .get(&event.event_data.room_id)
+        .map(|room| room.user_cookie.clone())
+}
+
+/// Choose the next upload to start, if any fits within the limits.
+///
+/// Uploads with a higher priority go first. Among the rest, rooms with fewer
+/// running uploads go first, then rooms that have waited the longest since
+/// their last upload started, then the queue order.
+fn pick<'a>(
+    config: &ManagerConfig,
+    state: &AppState,
+    candidates: &'a [QueuedUpload],
+    last_started: &HashMap<u64, Instant>,
+) -> Option<&'a QueuedUpload> {
+    let jobs = state.jobs.read().unwrap();
+    if jobs.len() >= config.workers.global {
+        return None;
+    }
+    let paused = state.paused.read().unwrap();
+
+    let per_room = |room_id: u64| jobs.values().filter(|job| job.room_id == room_id).count();
+    let per_account = |account: &Option<String>| {
+        jobs.values()
+            .filter(|job| account.is_some() && job.account == *account)
+            .count()
+    };
+
+    candidates
+        .iter()
+        .filter(|upload| !jobs.contains_key(&upload.event.event_id))
+        .filter(|upload| !paused.contains(upload.event.event_data.room_id))
+        .filter(|upload| match config.workers.per_room {
+            Some(limit) => per_room(upload.event.event_data.room_id) < limit,
+            None => true,
+        })
+        .filter(|upload| match config.workers.per_account {
+            Some(limit) => per_account(&account(config, &upload.event)) < limit,
+            None => true,
+        })
+        .min_by_key(|upload| {
+            let room_id = upload.event.event_data.room_id;
+            (
+                Reverse(upload.priority),
+                per_room(room_id),
+                last_started.get(&room_id).copied(),
+            )
+        })
+}
+
+fn start(
+    config: Arc<ManagerConfig>,
+    dao: web::Data<BiliupDao>,
+    state: web::Data<AppState>,
+    event: RecorderEvent,
+) {
+    let (abort, registration) = AbortHandle::new_pair();
+
+    // Register before spawning so that the scheduler does not pick it again.
+    state.jobs.write().unwrap().insert(
+        event.event_id.clone(),
+        JobState {
+            event_id: event.event_id.clone(),
+            room_id: event.event_data.room_id,
+            account: account(&config, &event),
+            started_at: chrono::Utc::now().naive_utc(),
+            cancelled: false,
+            abort,
+            progress: Progress::new(event.event_data.file_size),
+        },
+    );
+
+    tokio::spawn(async move {
+        let started = Instant::now();
+        let result = upload::upload(&config, &dao, &event, &state, registration).await;
+
+        let room_id = event.event_data.room_id.to_string();
+        let (line, outcome) = match &result {
+            Ok(uploaded) => (uploaded.line.as_deref().unwrap_or(""), "succeeded"),
+            Err(e) if e.class == ErrorClass::Cancelled => ("", "cancelled"),
+            Err(_) => ("", "failed"),
+        };
+        state
+            .metrics
+            .uploads
+            .with_label_values(&[room_id.as_str(), line, outcome])
+            .inc();
+        state
+            .metrics
+            .upload_duration
+            .with_label_values(&[room_id.as_str()])
+            .observe(started.elapsed().as_secs_f64());
+
+        match result {
+            Ok(_) => (),
+            Err(e) if e.class == ErrorClass::Cancelled => {
+                info!("Cancelled {}", event.event_id);
+
+                match dao
+                    .set_status(&event.event_id, UploadStatus::Cancelled)
+                    .await
+                {
+                    Ok(_) => state.publish_status(&event.event_id, UploadStatus::Cancelled),
+                    Err(e) => warn!("Failed to mark upload as cancelled: {}", e),
+                }
+            }
+            Err(e) => {
+                warn!("{}", e);
+
+                match handle_failure(&config, &dao, &event, &e).await {
+                    Ok(Some(status)) => state.publish_status(&event.event_id, status),
+                    Ok(None) => (),
+                    Err(e) => warn!("Failed to record upload failure: {}", e),
+                }
+            }
+        }
+
+        state.jobs.write().unwrap().remove(&event.event_id);
+        state.queue.notify_one();
+    });
+}
+
+/// Sleep until a new upload is queued or a postponed one becomes due.
+async fn wait_for_work(dao: &BiliupDao, state: &AppState) {
+    let next_attempt_at = match dao.next_attempt_at().await {
+        Ok(next_attempt_at) => next_attempt_at,
+        Err(e) => {
+            warn!("Failed to fetch next attempt time: {}", e);
+            Some(chrono::Utc::now() + chrono::Duration::seconds(5))
+        }
+    };
+
+    match next_attempt_at {
+        Some(next_attempt_at) => {
+            let delay = (next_attempt_at - chrono::Utc::now())
+                .to_std()
+                .unwrap_or_default();
+            let _ = tokio::time::timeout(delay, state.queue.notified()).await;
+        }
+        None => state.queue.notified().await,
+    }
+}
+
+/// Schedule a retry or give up. Returns the new status of the upload.
+async fn handle_failure(
+    config: &ManagerConfig,
+    dao: &BiliupDao,
+    event: &RecorderEvent,
+    error: &UploadError,
+) -> anyhow::Result<Option<UploadStatus>> {
+    // Cancelled while the scheduler was starting it.
+    if dao.get_status(&event.event_id).await? == Some(UploadStatus::Cancelled) {
+        return Ok(None);
+    }
+
+    let attempts = dao.add_attempt(&event.event_id, &error.to_string()).await?;
+    let max_attempts = config.retry.max_attempts(error.class);
+
+    if attempts >= max_attempts {
+        warn!(
+            "Giving up on {} after {} attempts",
+            event.event_id, attempts
+        );
+        dao.fail_upload(&event.event_id).await?;
+        return Ok(Some(UploadStatus::Failed));
+    }
+
+    let delay = config.retry.backoff(attempts);
+    info!(
+        "Retrying {} in {}s (attempt {}/{})",
+        event.event_id,
+        delay.as_secs(),
+        attempts + 1,
+        max_attempts
+    );
+    let status = if error.is_missing_file() {
+        UploadStatus::WaitingForFile
+    } else {
+        UploadStatus::Queued
+    };
+    let next_attempt_at = chrono::Utc::now() + chrono::Duration::from_std(delay)?;
+    dao.schedule_retry(&event.event_id, status, next_attempt_at)
+        .await?;
+    Ok(Some(status))
+}